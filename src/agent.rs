@@ -0,0 +1,176 @@
+use std::{
+    error::Error,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    process::Command,
+};
+use zeroize::Zeroizing;
+
+/// Location of gpg-agent's socket: what `gpgconf` reports, otherwise
+/// `S.gpg-agent` in `GNUPGHOME` or `~/.gnupg`.
+pub fn agent_socket() -> Option<PathBuf> {
+    let reported = Command::new("gpgconf")
+        .args(["--list-dirs", "agent-socket"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| {
+            let path = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            (!path.is_empty()).then(|| PathBuf::from(percent_decode(&path).as_str()))
+        });
+    reported.or_else(|| {
+        let home = std::env::var_os("GNUPGHOME")
+            .map(PathBuf::from)
+            .or_else(|| dirs::home_dir().map(|home| home.join(".gnupg")))?;
+        Some(home.join("S.gpg-agent"))
+    })
+}
+
+/// Asks the gpg-agent listening on `socket` for the passphrase cached under
+/// `cache_id`. If it is not cached the agent prompts through its pinentry,
+/// showing `description`, and caches the answer. Passphrases can be put in
+/// the cache ahead of time with `gpg-preset-passphrase`. Every buffer that
+/// holds the passphrase is wiped when dropped.
+#[cfg(unix)]
+pub fn get_passphrase(
+    socket: &Path,
+    cache_id: &str,
+    description: &str,
+) -> Result<Zeroizing<String>, Box<dyn Error>> {
+    let stream = std::os::unix::net::UnixStream::connect(socket)
+        .map_err(|e| format!("Cannot connect to gpg-agent at {}: {}", socket.display(), e))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+
+    read_response(&mut reader)?;
+    // Lets pinentry prompt on our terminal when run interactively.
+    if let Ok(tty) = std::env::var("GPG_TTY") {
+        writeln!(writer, "OPTION ttyname={}", tty)?;
+        read_response(&mut reader)?;
+    }
+    writeln!(
+        writer,
+        "GET_PASSPHRASE --data {} X X {}",
+        cache_id,
+        escape_argument(description)
+    )?;
+    let data = read_response(&mut reader)?;
+    Ok(percent_decode(&data))
+}
+
+#[cfg(not(unix))]
+pub fn get_passphrase(
+    _socket: &Path,
+    _cache_id: &str,
+    _description: &str,
+) -> Result<Zeroizing<String>, Box<dyn Error>> {
+    Err("gpg-agent passphrases are only supported on Unix".into())
+}
+
+/// Reads Assuan lines up to the final `OK` and returns the data sent
+/// before it, still percent-escaped. `ERR` lines become errors.
+fn read_response(reader: &mut impl BufRead) -> Result<Zeroizing<String>, Box<dyn Error>> {
+    // Sized for a whole Assuan line up front, so that growing it does not
+    // leave copies of the data behind.
+    let mut data = Zeroizing::new(String::with_capacity(1024));
+    loop {
+        let mut line = Zeroizing::new(String::with_capacity(1024));
+        if reader.read_line(&mut line)? == 0 {
+            return Err("gpg-agent closed the connection".into());
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line == "OK" || line.starts_with("OK ") {
+            return Ok(data);
+        } else if let Some(error) = line.strip_prefix("ERR ") {
+            return Err(format!("gpg-agent: {}", percent_decode(error).as_str()).into());
+        } else if let Some(chunk) = line.strip_prefix("D ") {
+            data.push_str(chunk);
+        } else if line.starts_with("INQUIRE") {
+            return Err(format!("gpg-agent asked for unsupported input: {}", line).into());
+        }
+        // Status (`S`) and comment (`#`) lines carry nothing we need.
+    }
+}
+
+/// Escapes a command argument: `+` stands for a space.
+fn escape_argument(text: &str) -> String {
+    let mut escaped = String::new();
+    for byte in text.bytes() {
+        match byte {
+            b' ' => escaped.push('+'),
+            b'+' | b'%' | b'"' | b'\r' | b'\n' => escaped.push_str(&format!("%{:02X}", byte)),
+            _ => escaped.push(byte as char),
+        }
+    }
+    escaped
+}
+
+fn percent_decode(text: &str) -> Zeroizing<String> {
+    let bytes = text.as_bytes();
+    let mut decoded = Zeroizing::new(Vec::with_capacity(bytes.len()));
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    Zeroizing::new(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn test_passphrase_from_agent() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("S.gpg-agent");
+        let listener = UnixListener::bind(&socket).unwrap();
+
+        let agent = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            writeln!(writer, "# comment").unwrap();
+            writeln!(writer, "OK Pleased to meet you").unwrap();
+            let mut request = String::new();
+            reader.read_line(&mut request).unwrap();
+            while request.starts_with("OPTION ") {
+                writeln!(writer, "OK").unwrap();
+                request.clear();
+                reader.read_line(&mut request).unwrap();
+            }
+            writeln!(writer, "S PROGRESS").unwrap();
+            writeln!(writer, "D pass%25phrase%0A+more").unwrap();
+            writeln!(writer, "OK").unwrap();
+            request
+        });
+
+        let passphrase = get_passphrase(&socket, "ABCDEF", "Unlock 50% of it").unwrap();
+        assert_eq!(passphrase.as_str(), "pass%phrase\n+more");
+        assert_eq!(
+            agent.join().unwrap(),
+            "GET_PASSPHRASE --data ABCDEF X X Unlock+50%25+of+it\n"
+        );
+    }
+
+    #[test]
+    fn test_agent_errors_are_reported() {
+        let mut response = "OK\nERR 83886179 Operation cancelled <Pinentry>\n".as_bytes();
+        read_response(&mut response).unwrap();
+        let err = read_response(&mut response).unwrap_err();
+        assert!(err.to_string().contains("Operation cancelled"));
+    }
+}
@@ -0,0 +1,411 @@
+use crate::{
+    collision::{civil_from_days, resolve_name},
+    config::{CleanupConfig, CollisionPolicy, EncryptedAction, OriginalAction},
+    pgp::signature_path,
+};
+use sequoia_openpgp::crypto::random;
+use std::{
+    cmp::Reverse,
+    error::Error,
+    fs::{self, OpenOptions},
+    io::{self, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const OVERWRITE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Deletes, archives or keeps the original once its upload is verified.
+/// `relative` is the file's directory relative to `watch_dir`. Safe to run
+/// again after an interruption. Returns whether the original is still in
+/// place.
+pub fn finish_original(
+    source: &Path,
+    relative: &Path,
+    config: &CleanupConfig,
+) -> Result<bool, Box<dyn Error>> {
+    match config.original {
+        OriginalAction::Delete if source.exists() => {
+            if config.dry_run {
+                report_dry_run(source, config.secure_delete_passes);
+                return Ok(true);
+            }
+            let deleted = if config.secure_delete_passes > 0 {
+                secure_delete(source, config.secure_delete_passes)
+            } else {
+                fs::remove_file(source)
+            };
+            match deleted {
+                Ok(()) => println!("Deleted original: {}", source.display()),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(format!(
+                        "Failed to delete original file: {} — {}",
+                        source.display(),
+                        e
+                    )
+                    .into())
+                }
+            }
+        }
+        OriginalAction::Archive if source.exists() => {
+            let mut dir = config.archive_dir.clone();
+            if config.archive_by_date {
+                dir.push(date_dir(SystemTime::now()));
+            }
+            let archived = move_into(source, &dir.join(relative))?;
+            println!(
+                "Archived original: {} -> {}",
+                source.display(),
+                archived.display()
+            );
+        }
+        OriginalAction::Keep => return Ok(true),
+        OriginalAction::Delete | OriginalAction::Archive => {}
+    }
+    Ok(false)
+}
+
+/// Keeps, deletes or moves the local `.pgp` copy and its detached signature
+/// to the sent folder, then applies the sent folder's retention limits.
+/// `relative` is the copy's directory relative to `encrypted_dir`.
+pub fn finish_encrypted(
+    encrypted: &Path,
+    relative: &Path,
+    config: &CleanupConfig,
+) -> Result<(), Box<dyn Error>> {
+    match config.encrypted {
+        EncryptedAction::Keep => {}
+        EncryptedAction::Delete if config.dry_run => {
+            for file in [encrypted, &signature_path(encrypted)] {
+                if file.exists() {
+                    report_dry_run(file, 0);
+                }
+            }
+        }
+        EncryptedAction::Delete => {
+            for file in [encrypted, &signature_path(encrypted)] {
+                match fs::remove_file(file) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+            }
+        }
+        EncryptedAction::Sent => {
+            let dir = config.sent_dir.join(relative);
+            if encrypted.exists() {
+                move_signed_into(encrypted, &dir)?;
+            } else if signature_path(encrypted).exists() {
+                // Left behind by an interrupted earlier attempt.
+                move_into(&signature_path(encrypted), &dir)?;
+            }
+            let max_age = config
+                .sent_max_age_days
+                .map(|days| Duration::from_secs(days * 86_400));
+            let removed = prune(
+                &config.sent_dir,
+                max_age,
+                config.sent_max_files,
+                config.dry_run,
+            )?;
+            if removed > 0 && !config.dry_run {
+                println!(
+                    "Removed {} old file(s) from {}",
+                    removed,
+                    config.sent_dir.display()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Moves `file` into `dir` without replacing anything already there,
+/// falling back to copy and delete across file systems.
+fn move_into(file: &Path, dir: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    fs::create_dir_all(dir)?;
+    let name = file
+        .file_name()
+        .ok_or("not a file")?
+        .to_string_lossy()
+        .into_owned();
+    let name = resolve_name(&name, CollisionPolicy::Counter, file, &mut |name| {
+        Ok(dir.join(name).exists())
+    })?
+    .unwrap_or(name);
+    let target = dir.join(name);
+    move_file(file, &target)?;
+    Ok(target)
+}
+
+/// Like [`move_into`], but picks a name that is free for both `file` and
+/// its detached signature and moves the signature along, so the pair keeps
+/// matching names.
+fn move_signed_into(file: &Path, dir: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    fs::create_dir_all(dir)?;
+    let name = file
+        .file_name()
+        .ok_or("not a file")?
+        .to_string_lossy()
+        .into_owned();
+    let name = resolve_name(&name, CollisionPolicy::Counter, file, &mut |name| {
+        let target = dir.join(name);
+        Ok(target.exists() || signature_path(&target).exists())
+    })?
+    .unwrap_or(name);
+    let target = dir.join(name);
+    let signature = signature_path(file);
+    if signature.exists() {
+        move_file(&signature, &signature_path(&target))?;
+    }
+    move_file(file, &target)?;
+    Ok(target)
+}
+
+/// Renames `from` to `to`, falling back to copy and delete across file
+/// systems.
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if fs::rename(from, to).is_err() {
+        fs::copy(from, to)?;
+        fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
+/// Deletes files below `dir` that are older than `max_age` or beyond the
+/// newest `max_files`, or only reports them if `dry_run` is set. Returns
+/// how many were selected.
+fn prune(
+    dir: &Path,
+    max_age: Option<Duration>,
+    max_files: Option<usize>,
+    dry_run: bool,
+) -> io::Result<usize> {
+    if max_age.is_none() && max_files.is_none() {
+        return Ok(0);
+    }
+    let mut files = Vec::new();
+    collect_files(dir, &mut files)?;
+    // Detached signatures go with the file they sign rather than counting
+    // towards the limit on their own.
+    files.retain(|(path, _)| path.extension().is_none_or(|ext| ext != "sig"));
+    files.sort_by_key(|(_, modified)| Reverse(*modified));
+
+    let now = SystemTime::now();
+    let mut removed = 0;
+    for (i, (path, modified)) in files.iter().enumerate() {
+        let too_old =
+            max_age.is_some_and(|max| now.duration_since(*modified).is_ok_and(|age| age > max));
+        if !too_old && max_files.is_none_or(|max| i < max) {
+            continue;
+        }
+        let signature = signature_path(path);
+        if dry_run {
+            report_dry_run(path, 0);
+            if signature.exists() {
+                report_dry_run(&signature, 0);
+            }
+            removed += 1;
+        } else {
+            match fs::remove_file(path) {
+                Ok(()) => removed += 1,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+            match fs::remove_file(&signature) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+    }
+    Ok(removed)
+}
+
+/// Overwrites the contents of `path` `passes` times with random data, the
+/// last pass with zeros, syncing after each pass, then truncates and
+/// unlinks it.
+///
+/// This only helps where writes land on the same blocks as the original
+/// data. On SSDs (wear levelling), copy-on-write or snapshotting file
+/// systems (btrfs, ZFS, APFS) and journaled data the old contents can
+/// survive; use full-disk encryption there.
+fn secure_delete(path: &Path, passes: u32) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    let len = file.metadata()?.len();
+    let mut buf = vec![0u8; OVERWRITE_BUFFER_SIZE];
+
+    for pass in 1..=passes {
+        file.seek(SeekFrom::Start(0))?;
+        let mut remaining = len;
+        while remaining > 0 {
+            let n = remaining.min(buf.len() as u64) as usize;
+            if pass < passes {
+                random(&mut buf[..n]).map_err(io::Error::other)?;
+            } else {
+                buf[..n].fill(0);
+            }
+            file.write_all(&buf[..n])?;
+            remaining -= n as u64;
+        }
+        file.sync_all()?;
+    }
+
+    file.set_len(0)?;
+    file.sync_all()?;
+    drop(file);
+    fs::remove_file(path)
+}
+
+fn report_dry_run(path: &Path, passes: u32) {
+    let size = fs::metadata(path).map_or(0, |m| m.len());
+    if passes > 0 {
+        println!(
+            "Dry run: would securely delete {} ({} bytes, {} pass(es))",
+            path.display(),
+            size,
+            passes
+        );
+    } else {
+        println!("Dry run: would delete {} ({} bytes)", path.display(), size);
+    }
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<(PathBuf, SystemTime)>) -> io::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            collect_files(&entry.path(), files)?;
+        } else {
+            files.push((entry.path(), metadata.modified()?));
+        }
+    }
+    Ok(())
+}
+
+/// `YYYY/MM/DD` for the UTC date of `time`.
+fn date_dir(time: SystemTime) -> PathBuf {
+    let days = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()) / 86_400;
+    let (year, month, day) = civil_from_days(days as i64);
+    PathBuf::from(format!("{:04}/{:02}/{:02}", year, month, day))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_archive_by_date_keeps_existing_copies() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = CleanupConfig {
+            original: OriginalAction::Archive,
+            archive_dir: dir.path().join("archive"),
+            archive_by_date: true,
+            ..CleanupConfig::default()
+        };
+        let source = dir.path().join("report.csv");
+        let archived = config
+            .archive_dir
+            .join(date_dir(SystemTime::now()))
+            .join("clients");
+
+        for _ in 0..2 {
+            fs::write(&source, "data").unwrap();
+            finish_original(&source, Path::new("clients"), &config).unwrap();
+            assert!(!source.exists());
+        }
+
+        assert!(archived.join("report.csv").exists());
+        assert!(archived.join("report_1.csv").exists());
+        // Already archived: running the step again is a no-op.
+        finish_original(&source, Path::new("clients"), &config).unwrap();
+    }
+
+    #[test]
+    fn test_prune_keeps_newest_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        let old = dir.path().join("sub/old.pgp");
+        fs::write(&old, "x").unwrap();
+        let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        fs::File::options()
+            .write(true)
+            .open(&old)
+            .unwrap()
+            .set_modified(an_hour_ago)
+            .unwrap();
+        for name in ["a.pgp", "b.pgp", "sub/old.pgp.sig"] {
+            fs::write(dir.path().join(name), "x").unwrap();
+        }
+
+        assert_eq!(prune(dir.path(), None, Some(2), true).unwrap(), 1);
+        assert!(old.exists());
+        assert_eq!(prune(dir.path(), None, Some(2), false).unwrap(), 1);
+        assert!(!old.exists());
+        assert!(!dir.path().join("sub/old.pgp.sig").exists());
+        assert_eq!(
+            prune(dir.path(), Some(Duration::from_secs(60)), None, false).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_signature_moves_to_sent_with_its_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = CleanupConfig {
+            encrypted: EncryptedAction::Sent,
+            sent_dir: dir.path().join("sent"),
+            ..CleanupConfig::default()
+        };
+        fs::create_dir(&config.sent_dir).unwrap();
+        // Only the signature's name is taken in the sent folder.
+        fs::write(config.sent_dir.join("report.pgp.sig"), "old").unwrap();
+        let encrypted = dir.path().join("report.pgp");
+        fs::write(&encrypted, "data").unwrap();
+        fs::write(signature_path(&encrypted), "sig").unwrap();
+
+        finish_encrypted(&encrypted, Path::new(""), &config).unwrap();
+
+        assert!(!encrypted.exists());
+        assert!(!signature_path(&encrypted).exists());
+        assert!(!config.sent_dir.join("report.pgp").exists());
+        assert_eq!(
+            fs::read(config.sent_dir.join("report_1.pgp")).unwrap(),
+            b"data"
+        );
+        assert_eq!(
+            fs::read(config.sent_dir.join("report_1.pgp.sig")).unwrap(),
+            b"sig"
+        );
+    }
+
+    #[test]
+    fn test_secure_delete_and_dry_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("secret.txt");
+        fs::write(&source, "x".repeat(100_000)).unwrap();
+        let mut config = CleanupConfig {
+            secure_delete_passes: 2,
+            dry_run: true,
+            ..CleanupConfig::default()
+        };
+
+        assert!(finish_original(&source, Path::new(""), &config).unwrap());
+        assert_eq!(fs::read(&source).unwrap().len(), 100_000);
+
+        config.dry_run = false;
+        assert!(!finish_original(&source, Path::new(""), &config).unwrap());
+        assert!(!source.exists());
+    }
+
+    #[test]
+    fn test_date_dir() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(date_dir(time), PathBuf::from("2023/11/14"));
+    }
+}
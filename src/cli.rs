@@ -0,0 +1,250 @@
+use crate::config::{
+    CollisionPolicy, Config, EncryptedAction, HostKeyPolicy, OriginalAction, SftpAuth, UploadVerify,
+};
+use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "vault_sync",
+    version,
+    about = "Encrypts files dropped into a folder with OpenPGP and uploads them via SFTP"
+)]
+pub struct Cli {
+    /// TOML config file (defaults to VAULTSYNC_CONFIG, then ./vaultsync.toml)
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub overrides: Overrides,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Watch WATCH_DIR and encrypt, upload and clean up new files (default)
+    Watch,
+    /// Encrypt files into ENCRYPTED_DIR with PGP_PUBLIC_KEY
+    Encrypt {
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+    /// Decrypt .pgp files into DECRYPTED_DIR with PGP_PRIVATE_KEY
+    Decrypt {
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+    /// Upload files to SFTP_REMOTE_DIR
+    Upload {
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+    /// Check a file against its detached signature and PGP_TRUSTED_SIGNERS
+    Verify {
+        file: PathBuf,
+        /// Detached signature (defaults to FILE.sig)
+        signature: Option<PathBuf>,
+        /// Trusted key file, keyring, directory or group; replaces PGP_TRUSTED_SIGNERS
+        #[arg(long)]
+        trusted: Vec<String>,
+    },
+    /// Generate a new OpenPGP key pair
+    Keygen {
+        /// User ID bound to the new key
+        #[arg(long, default_value = "VaultSync")]
+        user_id: String,
+        /// Where to write the armored public key
+        #[arg(long, default_value = "keys/recipient.asc")]
+        public_out: PathBuf,
+        /// Where to write the armored secret key
+        #[arg(long, default_value = "keys/secret.asc")]
+        secret_out: PathBuf,
+        /// Protect the secret key with the configured private key passphrase
+        #[arg(long)]
+        protect: bool,
+        /// Overwrite existing key files
+        #[arg(long)]
+        force: bool,
+    },
+    /// Register VaultSync to start automatically on login
+    InstallService,
+    /// Validate the configuration and exit
+    CheckConfig,
+}
+
+/// Command-line values that take precedence over the config file and environment.
+#[derive(Args, Debug, Default)]
+pub struct Overrides {
+    #[arg(long, global = true)]
+    pub watch_dir: Option<PathBuf>,
+    #[arg(long, global = true)]
+    pub encrypted_dir: Option<PathBuf>,
+    #[arg(long, global = true)]
+    pub decrypted_dir: Option<PathBuf>,
+    #[arg(long, global = true)]
+    pub state_dir: Option<PathBuf>,
+    #[arg(long, global = true)]
+    pub collision: Option<CollisionPolicy>,
+    #[arg(long, global = true)]
+    pub cleanup_original: Option<OriginalAction>,
+    #[arg(long, global = true)]
+    pub secure_delete_passes: Option<u32>,
+    /// Report what cleanup would delete without deleting anything
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+    #[arg(long, global = true)]
+    pub archive_dir: Option<PathBuf>,
+    #[arg(long, global = true)]
+    pub cleanup_encrypted: Option<EncryptedAction>,
+    #[arg(long, global = true)]
+    pub workers: Option<usize>,
+    #[arg(long, global = true)]
+    pub public_key: Option<PathBuf>,
+    #[arg(long, global = true)]
+    pub private_key: Option<PathBuf>,
+    #[arg(long, global = true)]
+    pub sftp_host: Option<String>,
+    #[arg(long, global = true)]
+    pub sftp_port: Option<u16>,
+    #[arg(long, global = true)]
+    pub sftp_user: Option<String>,
+    #[arg(long, global = true)]
+    pub sftp_auth: Option<SftpAuth>,
+    #[arg(long, global = true)]
+    pub sftp_private_key: Option<PathBuf>,
+    #[arg(long, global = true)]
+    pub sftp_known_hosts: Option<PathBuf>,
+    #[arg(long, global = true)]
+    pub sftp_host_key_policy: Option<HostKeyPolicy>,
+    #[arg(long, global = true)]
+    pub sftp_remote_dir: Option<String>,
+    #[arg(long, global = true)]
+    pub sftp_connections: Option<usize>,
+    #[arg(long, global = true)]
+    pub sftp_bandwidth_limit_kbps: Option<u64>,
+    #[arg(long, global = true)]
+    pub sftp_verify: Option<UploadVerify>,
+    #[arg(long, global = true)]
+    pub sftp_retry: Option<u32>,
+    #[arg(long, global = true)]
+    pub sftp_retry_backoff_ms: Option<u64>,
+    #[arg(long, global = true)]
+    pub sftp_retry_max_backoff_ms: Option<u64>,
+}
+
+impl Overrides {
+    pub fn apply_to(&self, config: &mut Config) {
+        if let Some(dir) = &self.watch_dir {
+            config.watch_dir = dir.clone();
+        }
+        if let Some(dir) = &self.encrypted_dir {
+            config.encrypted_dir = dir.clone();
+        }
+        if let Some(dir) = &self.decrypted_dir {
+            config.decrypted_dir = dir.clone();
+        }
+        if let Some(dir) = &self.state_dir {
+            config.state_dir = dir.clone();
+        }
+        if let Some(policy) = self.collision {
+            config.collision = policy;
+        }
+        if let Some(action) = self.cleanup_original {
+            config.cleanup.original = action;
+        }
+        if let Some(passes) = self.secure_delete_passes {
+            config.cleanup.secure_delete_passes = passes;
+        }
+        if self.dry_run {
+            config.cleanup.dry_run = true;
+        }
+        if let Some(dir) = &self.archive_dir {
+            config.cleanup.archive_dir = dir.clone();
+        }
+        if let Some(action) = self.cleanup_encrypted {
+            config.cleanup.encrypted = action;
+        }
+        if let Some(count) = self.workers {
+            config.workers.count = count;
+        }
+        if let Some(path) = &self.public_key {
+            config.pgp.public_key = path.clone();
+        }
+        if let Some(path) = &self.private_key {
+            config.pgp.private_key = Some(path.clone());
+        }
+        if let Some(host) = &self.sftp_host {
+            config.sftp.host = host.clone();
+        }
+        if let Some(port) = self.sftp_port {
+            config.sftp.port = port;
+        }
+        if let Some(user) = &self.sftp_user {
+            config.sftp.user = user.clone();
+        }
+        if let Some(auth) = self.sftp_auth {
+            config.sftp.auth = auth;
+        }
+        if let Some(path) = &self.sftp_private_key {
+            config.sftp.private_key = Some(path.clone());
+        }
+        if let Some(path) = &self.sftp_known_hosts {
+            config.sftp.known_hosts = Some(path.clone());
+        }
+        if let Some(policy) = self.sftp_host_key_policy {
+            config.sftp.host_key_policy = policy;
+        }
+        if let Some(dir) = &self.sftp_remote_dir {
+            config.sftp.remote_dir = dir.clone();
+        }
+        if let Some(connections) = self.sftp_connections {
+            config.sftp.connections = connections;
+        }
+        if let Some(limit) = self.sftp_bandwidth_limit_kbps {
+            config.sftp.bandwidth_limit_kbps = Some(limit);
+        }
+        if let Some(verify) = self.sftp_verify {
+            config.sftp.verify = verify;
+        }
+        if let Some(retry) = self.sftp_retry {
+            config.sftp.retry = retry;
+        }
+        if let Some(backoff_ms) = self.sftp_retry_backoff_ms {
+            config.sftp.retry_backoff_ms = backoff_ms;
+        }
+        if let Some(max_backoff_ms) = self.sftp_retry_max_backoff_ms {
+            config.sftp.retry_max_backoff_ms = max_backoff_ms;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_defaults_to_no_subcommand() {
+        let cli = Cli::try_parse_from(["vault_sync"]).unwrap();
+        assert!(cli.command.is_none());
+    }
+
+    #[test]
+    fn test_parse_global_override_after_subcommand() {
+        let cli =
+            Cli::try_parse_from(["vault_sync", "upload", "a.pgp", "--sftp-port", "2222"]).unwrap();
+        assert_eq!(cli.overrides.sftp_port, Some(2222));
+        assert!(matches!(cli.command, Some(Command::Upload { ref files }) if files.len() == 1));
+    }
+
+    #[test]
+    fn test_overrides_take_precedence_over_config() {
+        let cli = Cli::try_parse_from(["vault_sync", "--watch-dir", "flag-dir", "watch"]).unwrap();
+        let mut config = Config::from_toml_str("watch_dir = \"file-dir\"").unwrap();
+
+        cli.overrides.apply_to(&mut config);
+
+        assert_eq!(config.watch_dir, PathBuf::from("flag-dir"));
+    }
+}
@@ -0,0 +1,172 @@
+use crate::config::CollisionPolicy;
+use crate::transfer::sha256_prefix;
+use std::{
+    error::Error,
+    fs::File,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Reports whether a name already exists at the destination.
+pub type Taken<'a> = dyn FnMut(&str) -> Result<bool, Box<dyn Error>> + 'a;
+
+/// Picks the name to store `name` under when `taken` reports which names
+/// already exist at the destination. Returns `None` if the policy is `skip`
+/// and the name is taken. The marker is inserted before the extensions, so
+/// `report.csv.pgp` becomes e.g. `report_3.csv.pgp`.
+pub fn resolve_name(
+    name: &str,
+    policy: CollisionPolicy,
+    source: &Path,
+    taken: &mut Taken,
+) -> Result<Option<String>, Box<dyn Error>> {
+    match policy {
+        CollisionPolicy::Overwrite => Ok(Some(name.to_string())),
+        _ if !taken(name)? => Ok(Some(name.to_string())),
+        CollisionPolicy::Skip => Ok(None),
+        CollisionPolicy::Counter => first_free(name, taken).map(Some),
+        CollisionPolicy::Timestamp => {
+            let stamped = with_marker(name, &utc_timestamp(SystemTime::now()));
+            if taken(&stamped)? {
+                first_free(&stamped, taken).map(Some)
+            } else {
+                Ok(Some(stamped))
+            }
+        }
+        CollisionPolicy::Hash => {
+            // Same name, same content: replacing the existing file loses nothing.
+            let mut file = File::open(source)?;
+            let len = file.metadata()?.len();
+            let digest = sha256_prefix(&mut file, len)?;
+            let hex: String = digest[..4].iter().map(|b| format!("{:02x}", b)).collect();
+            Ok(Some(with_marker(name, &hex)))
+        }
+    }
+}
+
+/// `name` with the lowest counter that is not taken.
+fn first_free(name: &str, taken: &mut Taken) -> Result<String, Box<dyn Error>> {
+    let mut counter = 1;
+    loop {
+        let candidate = with_marker(name, &counter.to_string());
+        if !taken(&candidate)? {
+            return Ok(candidate);
+        }
+        counter += 1;
+    }
+}
+
+/// Inserts `_<marker>` before the first extension of `name`, ignoring a
+/// leading dot.
+fn with_marker(name: &str, marker: &str) -> String {
+    let split = name
+        .char_indices()
+        .skip(1)
+        .find(|&(_, c)| c == '.')
+        .map_or(name.len(), |(i, _)| i);
+    format!("{}_{}{}", &name[..split], marker, &name[split..])
+}
+
+/// `YYYYMMDDTHHMMSSZ` in UTC.
+fn utc_timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Converts days since 1970-01-01 to a (year, month, day) date in the
+/// proleptic Gregorian calendar.
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::HashSet, time::Duration};
+
+    fn resolve(name: &str, policy: CollisionPolicy, existing: &[&str]) -> Option<String> {
+        let existing: HashSet<&str> = existing.iter().copied().collect();
+        resolve_name(name, policy, Path::new("unused"), &mut |n| {
+            Ok(existing.contains(n))
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_free_name_is_kept() {
+        for policy in [
+            CollisionPolicy::Skip,
+            CollisionPolicy::Counter,
+            CollisionPolicy::Timestamp,
+        ] {
+            assert_eq!(
+                resolve("a.csv.pgp", policy, &[]).as_deref(),
+                Some("a.csv.pgp")
+            );
+        }
+    }
+
+    #[test]
+    fn test_policies_on_collision() {
+        let taken = ["a.csv.pgp", "a_1.csv.pgp"];
+        assert_eq!(
+            resolve("a.csv.pgp", CollisionPolicy::Overwrite, &taken).as_deref(),
+            Some("a.csv.pgp")
+        );
+        assert_eq!(resolve("a.csv.pgp", CollisionPolicy::Skip, &taken), None);
+        assert_eq!(
+            resolve("a.csv.pgp", CollisionPolicy::Counter, &taken).as_deref(),
+            Some("a_2.csv.pgp")
+        );
+        let stamped = resolve("a.csv.pgp", CollisionPolicy::Timestamp, &taken).unwrap();
+        assert!(stamped.starts_with("a_20") && stamped.ends_with("Z.csv.pgp"));
+    }
+
+    #[test]
+    fn test_hash_policy_uses_source_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("a.csv");
+        std::fs::write(&source, "abc").unwrap();
+
+        let name = resolve_name("a.csv.pgp", CollisionPolicy::Hash, &source, &mut |_| {
+            Ok(true)
+        })
+        .unwrap()
+        .unwrap();
+
+        // SHA-256("abc") starts with ba7816bf.
+        assert_eq!(name, "a_ba7816bf.csv.pgp");
+    }
+
+    #[test]
+    fn test_with_marker() {
+        assert_eq!(with_marker("report.csv.pgp", "2"), "report_2.csv.pgp");
+        assert_eq!(with_marker("README", "2"), "README_2");
+        assert_eq!(with_marker(".env.pgp", "2"), ".env_2.pgp");
+    }
+
+    #[test]
+    fn test_utc_timestamp() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(utc_timestamp(time), "20231114T221320Z");
+    }
+}
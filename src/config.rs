@@ -1,18 +1,1437 @@
 #![allow(dead_code)]
+use crate::filter::{FileFilter, Rule};
 use aes_gcm::Aes256Gcm;
 use aes_gcm::Key;
 use base64::{engine::general_purpose, Engine as _};
-use dotenv::dotenv;
+use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::env;
+use std::fmt;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
-pub fn load_watch_dir() -> String {
-    dotenv().ok();
-    let val = env::var("WATCH_DIR").expect("WATCH_DIR must be set in .env");
-    println!("{}", val);
-    val
+use std::str::FromStr;
+
+/// Default config file looked up in the working directory.
+pub const DEFAULT_CONFIG_FILE: &str = "vaultsync.toml";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub watch_dir: PathBuf,
+    pub encrypted_dir: PathBuf,
+    pub decrypted_dir: PathBuf,
+    /// Holds the job journal that survives restarts.
+    pub state_dir: PathBuf,
+    /// Applied to both the encrypted file and the remote destination.
+    pub collision: CollisionPolicy,
+    pub pgp: PgpConfig,
+    pub sftp: SftpConfig,
+    pub queue: QueueConfig,
+    pub watch: WatchConfig,
+    pub workers: WorkersConfig,
+    pub filter: FilterConfig,
+    pub cleanup: CleanupConfig,
+    /// Servers that routes can send files to, besides `[sftp]`.
+    pub servers: BTreeMap<String, SftpConfig>,
+    /// Checked in order; the first route matching a file applies.
+    #[serde(rename = "route")]
+    pub routes: Vec<RouteConfig>,
+    /// Folders watched in addition to `watch_dir`.
+    #[serde(rename = "root")]
+    pub roots: Vec<WatchRootConfig>,
+    /// Unparsable environment variables, reported by `validate` together
+    /// with everything else.
+    #[serde(skip)]
+    pub(crate) env_problems: Vec<String>,
+}
+
+/// A watched folder with its own output directory, recipients and
+/// destination. Unset fields fall back to the top-level settings.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatchRootConfig {
+    pub dir: PathBuf,
+    pub encrypted_dir: Option<PathBuf>,
+    /// Replaces the default recipients: group names or key paths.
+    pub recipients: Option<Vec<String>>,
+    /// Name of a `[servers]` entry; `[sftp]` otherwise.
+    pub server: Option<String>,
+    /// Overrides the server's remote directory.
+    pub remote_dir: Option<String>,
+}
+
+/// Overrides where and to whom matching files are sent. Unset fields fall
+/// back to the top-level settings.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RouteConfig {
+    /// `.gitignore`-style pattern relative to `watch_dir`, such as
+    /// `clients/acme/**`. Also matches files below a matching directory.
+    #[serde(rename = "match")]
+    pub pattern: String,
+    /// Replaces the default recipients: group names or key paths.
+    pub recipients: Option<Vec<String>>,
+    /// Name of a `[servers]` entry; `[sftp]` otherwise.
+    pub server: Option<String>,
+    /// Overrides the server's remote directory.
+    pub remote_dir: Option<String>,
+    /// Changes to `[cleanup]` for matching files.
+    pub cleanup: CleanupOverride,
+}
+
+/// What happens to the original and to the local encrypted copy once an
+/// upload has been verified.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CleanupConfig {
+    pub original: OriginalAction,
+    /// Overwrite deleted originals this many times before unlinking them;
+    /// 0 unlinks only.
+    pub secure_delete_passes: u32,
+    /// Report what would be deleted instead of deleting it.
+    pub dry_run: bool,
+    /// Archived originals keep their path relative to `watch_dir` below this.
+    pub archive_dir: PathBuf,
+    /// Archive under `YYYY/MM/DD/` (UTC upload date).
+    pub archive_by_date: bool,
+    pub encrypted: EncryptedAction,
+    /// Sent `.pgp` files keep their path relative to `encrypted_dir` below this.
+    pub sent_dir: PathBuf,
+    /// Sent files older than this are deleted.
+    pub sent_max_age_days: Option<u64>,
+    /// Only the newest sent files are kept.
+    pub sent_max_files: Option<usize>,
+}
+
+/// A route's changes to `[cleanup]`; keys it leaves out keep the top-level
+/// value, including any environment or command-line override.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CleanupOverride {
+    pub original: Option<OriginalAction>,
+    pub secure_delete_passes: Option<u32>,
+    pub dry_run: Option<bool>,
+    pub archive_dir: Option<PathBuf>,
+    pub archive_by_date: Option<bool>,
+    pub encrypted: Option<EncryptedAction>,
+    pub sent_dir: Option<PathBuf>,
+    pub sent_max_age_days: Option<u64>,
+    pub sent_max_files: Option<usize>,
+}
+
+impl CleanupOverride {
+    /// `base` with this route's changes applied. A route can only make
+    /// cleanup more careful: a global dry run stays on and the global
+    /// secure-delete pass count is a minimum.
+    pub fn over(&self, base: &CleanupConfig) -> CleanupConfig {
+        CleanupConfig {
+            original: self.original.unwrap_or(base.original),
+            secure_delete_passes: self
+                .secure_delete_passes
+                .map_or(base.secure_delete_passes, |passes| {
+                    passes.max(base.secure_delete_passes)
+                }),
+            dry_run: base.dry_run || self.dry_run.unwrap_or(false),
+            archive_dir: self
+                .archive_dir
+                .clone()
+                .unwrap_or_else(|| base.archive_dir.clone()),
+            archive_by_date: self.archive_by_date.unwrap_or(base.archive_by_date),
+            encrypted: self.encrypted.unwrap_or(base.encrypted),
+            sent_dir: self
+                .sent_dir
+                .clone()
+                .unwrap_or_else(|| base.sent_dir.clone()),
+            sent_max_age_days: self.sent_max_age_days.or(base.sent_max_age_days),
+            sent_max_files: self.sent_max_files.or(base.sent_max_files),
+        }
+    }
+}
+
+/// Which files in `watch_dir` are processed. Patterns use `.gitignore`
+/// syntax.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilterConfig {
+    /// If not empty, only files matching one of these are processed.
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    /// Name of per-directory ignore files; empty disables them.
+    pub ignore_file: String,
+    /// Size limits in bytes.
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+}
+
+/// Parallelism of the encrypt-and-upload pipeline.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorkersConfig {
+    /// Number of worker threads processing jobs.
+    pub count: usize,
+    /// Jobs handed to workers but not yet started; further jobs wait in the journal.
+    pub queue_capacity: usize,
+    /// Maximum number of files being encrypted at once.
+    pub encrypt_concurrency: usize,
+    /// Maximum number of files being uploaded at once (also capped by sftp.connections).
+    pub upload_concurrency: usize,
+}
+
+/// How `watch_dir` is monitored.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatchConfig {
+    /// Rescan `watch_dir` this often to catch files the watcher missed; 0 disables it.
+    pub scan_interval_secs: u64,
+    /// A file is picked up once its size and mtime have not changed for this long.
+    pub quiet_period_ms: u64,
+    /// Pick up a file as soon as its writer closes it (Linux only).
+    pub close_write: bool,
+}
+
+/// When jobs that failed every upload attempt are tried again.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+    /// Wait before the first retry of a failed job; doubled for each further failure.
+    pub retry_interval_secs: u64,
+    pub retry_max_interval_secs: u64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PgpConfig {
+    pub public_key: PathBuf,
+    /// Further recipients of every file: names from `groups`, key files,
+    /// keyrings or directories of them.
+    pub recipients: Vec<String>,
+    /// Named sets of key files, keyrings or directories.
+    pub groups: BTreeMap<String, Vec<PathBuf>>,
+    pub private_key: Option<PathBuf>,
+    pub private_key_passphrase: Option<String>,
+    /// Secret key that signs every file before it is encrypted; files are
+    /// not signed when unset.
+    pub signing_key: Option<PathBuf>,
+    pub signing_key_passphrase: Option<String>,
+    /// File whose first line is the signing key's passphrase.
+    pub signing_key_passphrase_file: Option<PathBuf>,
+    /// Command that prints the passphrase, e.g. a password manager or
+    /// keyring lookup.
+    pub signing_key_passphrase_command: Option<String>,
+    /// Ask gpg-agent for the passphrase, cached under the signing key's
+    /// fingerprint.
+    pub signing_key_passphrase_agent: bool,
+    /// Writes a separate `.sig` next to each `.pgp` and uploads it too.
+    pub detached_signature: DetachedSignature,
+    /// ASCII-armor detached signatures instead of writing binary ones.
+    pub detached_signature_armor: bool,
+    /// Certificates `verify` accepts signatures from, as for `recipients`.
+    pub trusted_signers: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SftpConfig {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    pub auth: SftpAuth,
+    pub password: Option<String>,
+    pub private_key: Option<PathBuf>,
+    pub private_key_passphrase: Option<String>,
+    pub known_hosts: Option<PathBuf>,
+    pub host_key_fingerprint: Option<String>,
+    pub host_key_policy: HostKeyPolicy,
+    pub remote_dir: String,
+    /// Maximum number of SFTP sessions kept open at once.
+    pub connections: usize,
+    /// Size of the buffer used to stream each upload.
+    pub buffer_size_kb: usize,
+    /// Upload speed cap in KiB/s per transfer; unlimited when unset.
+    pub bandwidth_limit_kbps: Option<u64>,
+    /// Continue a failed upload from the size already on the server.
+    pub resume: bool,
+    pub resume_verify: ResumeVerify,
+    /// How the uploaded copy is checked before the original is deleted.
+    pub verify: UploadVerify,
+    /// Uploads are written as `<prefix><name><suffix>` and renamed once complete.
+    pub temp_prefix: String,
+    pub temp_suffix: String,
+    pub retry: u32,
+    /// Delay before the first retry; doubled for each further attempt.
+    pub retry_backoff_ms: u64,
+    /// Upper bound on the delay between attempts.
+    pub retry_max_backoff_ms: u64,
+}
+
+/// How the SFTP session authenticates after the handshake.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SftpAuth {
+    #[default]
+    Password,
+    Key,
+    Agent,
+    KeyboardInteractive,
+}
+
+impl FromStr for SftpAuth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "password" => Ok(SftpAuth::Password),
+            "key" => Ok(SftpAuth::Key),
+            "agent" => Ok(SftpAuth::Agent),
+            "keyboard-interactive" => Ok(SftpAuth::KeyboardInteractive),
+            other => Err(format!(
+                "unknown SFTP auth method '{}' (expected password, key, agent or keyboard-interactive)",
+                other
+            )),
+        }
+    }
+}
+
+/// What to do when the server's host key is not already trusted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HostKeyPolicy {
+    /// Refuse hosts that are not already in known_hosts.
+    Strict,
+    /// Record unknown hosts on first connect, refuse changed keys.
+    #[default]
+    AcceptNew,
+    /// Skip verification entirely.
+    Insecure,
+}
+
+impl FromStr for HostKeyPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(HostKeyPolicy::Strict),
+            "accept-new" => Ok(HostKeyPolicy::AcceptNew),
+            "insecure" => Ok(HostKeyPolicy::Insecure),
+            other => Err(format!(
+                "unknown host key policy '{}' (expected strict, accept-new or insecure)",
+                other
+            )),
+        }
+    }
+}
+
+/// How a partial remote file is checked before an upload resumes from it.
+/// Its contents are always compared; only where the hash is computed differs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResumeVerify {
+    /// Hash the remote prefix on the server with `head -c` and `sha256sum`.
+    Sha256sum,
+    /// Read the remote prefix back over SFTP and hash it locally.
+    #[default]
+    #[serde(alias = "sha256")]
+    ReadBack,
+}
+
+impl FromStr for ResumeVerify {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha256sum" => Ok(ResumeVerify::Sha256sum),
+            "read-back" | "sha256" => Ok(ResumeVerify::ReadBack),
+            other => Err(format!(
+                "unknown resume verification '{}' (expected sha256sum or read-back)",
+                other
+            )),
+        }
+    }
+}
+
+/// What to do when an encrypted file's name is already taken locally or on
+/// the server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CollisionPolicy {
+    /// Replace the existing file.
+    #[default]
+    Overwrite,
+    /// Leave the new file in the watch folder untouched.
+    Skip,
+    /// Append a UTC timestamp, e.g. `report_20250101T120000Z.csv.pgp`.
+    Timestamp,
+    /// Append the lowest free counter, e.g. `report_2.csv.pgp`.
+    Counter,
+    /// Append the start of the file's SHA-256, e.g. `report_ba7816bf.csv.pgp`.
+    Hash,
+}
+
+impl FromStr for CollisionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "overwrite" => Ok(CollisionPolicy::Overwrite),
+            "skip" => Ok(CollisionPolicy::Skip),
+            "timestamp" => Ok(CollisionPolicy::Timestamp),
+            "counter" => Ok(CollisionPolicy::Counter),
+            "hash" => Ok(CollisionPolicy::Hash),
+            other => Err(format!(
+                "unknown collision policy '{}' (expected overwrite, skip, timestamp, counter or hash)",
+                other
+            )),
+        }
+    }
+}
+
+/// What to do with the original file after a verified upload.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OriginalAction {
+    #[default]
+    Delete,
+    /// Move it to `cleanup.archive_dir`.
+    Archive,
+    /// Leave it in place; it is not picked up again unless it changes.
+    Keep,
+}
+
+impl FromStr for OriginalAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "delete" => Ok(OriginalAction::Delete),
+            "archive" => Ok(OriginalAction::Archive),
+            "keep" => Ok(OriginalAction::Keep),
+            other => Err(format!(
+                "unknown original action '{}' (expected delete, archive or keep)",
+                other
+            )),
+        }
+    }
+}
+
+/// What to do with the local `.pgp` copy after a verified upload.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EncryptedAction {
+    #[default]
+    Keep,
+    Delete,
+    /// Move it to `cleanup.sent_dir`, where retention limits apply.
+    Sent,
+}
+
+impl FromStr for EncryptedAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(EncryptedAction::Keep),
+            "delete" => Ok(EncryptedAction::Delete),
+            "sent" => Ok(EncryptedAction::Sent),
+            other => Err(format!(
+                "unknown encrypted copy action '{}' (expected keep, delete or sent)",
+                other
+            )),
+        }
+    }
+}
+
+/// What a detached signature covers, if one is written at all.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DetachedSignature {
+    #[default]
+    Off,
+    /// The original file, checked by the recipient after decrypting.
+    Plaintext,
+    /// The uploaded `.pgp`, checkable without the private key.
+    Ciphertext,
+}
+
+impl FromStr for DetachedSignature {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(DetachedSignature::Off),
+            "plaintext" => Ok(DetachedSignature::Plaintext),
+            "ciphertext" => Ok(DetachedSignature::Ciphertext),
+            other => Err(format!(
+                "unknown detached signature mode '{}' (expected off, plaintext or ciphertext)",
+                other
+            )),
+        }
+    }
+}
+
+/// How an uploaded file is compared with the local copy.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UploadVerify {
+    /// Compare the remote size reported by `stat`.
+    #[default]
+    Size,
+    /// Also compare SHA-256 computed on the server with `sha256sum`.
+    Sha256sum,
+    /// Also compare SHA-256 of the file read back over SFTP.
+    ReadBack,
+}
+
+impl FromStr for UploadVerify {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "size" => Ok(UploadVerify::Size),
+            "sha256sum" => Ok(UploadVerify::Sha256sum),
+            "read-back" => Ok(UploadVerify::ReadBack),
+            other => Err(format!(
+                "unknown upload verification '{}' (expected size, sha256sum or read-back)",
+                other
+            )),
+        }
+    }
+}
+
+impl SftpConfig {
+    /// The configured known_hosts file, or `~/.ssh/known_hosts`.
+    pub fn known_hosts_path(&self) -> Option<PathBuf> {
+        self.known_hosts
+            .clone()
+            .or_else(|| dirs::home_dir().map(|home| home.join(".ssh/known_hosts")))
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            watch_dir: PathBuf::new(),
+            encrypted_dir: PathBuf::from("encrypted"),
+            decrypted_dir: PathBuf::from("decrypted"),
+            state_dir: PathBuf::from("state"),
+            collision: CollisionPolicy::default(),
+            pgp: PgpConfig::default(),
+            sftp: SftpConfig::default(),
+            queue: QueueConfig::default(),
+            watch: WatchConfig::default(),
+            workers: WorkersConfig::default(),
+            filter: FilterConfig::default(),
+            cleanup: CleanupConfig::default(),
+            servers: BTreeMap::new(),
+            routes: Vec::new(),
+            roots: Vec::new(),
+            env_problems: Vec::new(),
+        }
+    }
+}
+
+impl Default for CleanupConfig {
+    fn default() -> Self {
+        CleanupConfig {
+            original: OriginalAction::default(),
+            secure_delete_passes: 0,
+            dry_run: false,
+            archive_dir: PathBuf::from("archive"),
+            archive_by_date: false,
+            encrypted: EncryptedAction::default(),
+            sent_dir: PathBuf::from("sent"),
+            sent_max_age_days: None,
+            sent_max_files: None,
+        }
+    }
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        FilterConfig {
+            include: Vec::new(),
+            // Editor swap files, Office lock files, partial downloads and
+            // hidden files.
+            exclude: [
+                ".*",
+                "*~",
+                "~$*",
+                "*.swp",
+                "*.tmp",
+                "*.part",
+                "*.partial",
+                "*.crdownload",
+            ]
+            .map(String::from)
+            .to_vec(),
+            ignore_file: ".vaultsyncignore".to_string(),
+            min_size: None,
+            max_size: None,
+        }
+    }
+}
+
+impl Default for WorkersConfig {
+    fn default() -> Self {
+        WorkersConfig {
+            count: 2,
+            queue_capacity: 16,
+            encrypt_concurrency: 2,
+            upload_concurrency: 2,
+        }
+    }
+}
+
+impl Default for WatchConfig {
+    fn default() -> Self {
+        WatchConfig {
+            scan_interval_secs: 300,
+            quiet_period_ms: 2000,
+            close_write: true,
+        }
+    }
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            retry_interval_secs: 300,
+            retry_max_interval_secs: 3600,
+        }
+    }
+}
+
+impl Default for SftpConfig {
+    fn default() -> Self {
+        SftpConfig {
+            host: String::new(),
+            port: 22,
+            user: String::new(),
+            auth: SftpAuth::default(),
+            password: None,
+            private_key: None,
+            private_key_passphrase: None,
+            known_hosts: None,
+            host_key_fingerprint: None,
+            host_key_policy: HostKeyPolicy::default(),
+            remote_dir: String::new(),
+            connections: 1,
+            buffer_size_kb: 64,
+            bandwidth_limit_kbps: None,
+            resume: true,
+            resume_verify: ResumeVerify::default(),
+            verify: UploadVerify::default(),
+            temp_prefix: ".".to_string(),
+            temp_suffix: ".partial".to_string(),
+            retry: 3,
+            retry_backoff_ms: 1000,
+            retry_max_backoff_ms: 60_000,
+        }
+    }
+}
+
+/// The settings a command needs before it can run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Need {
+    WatchDir,
+    PublicKey,
+    PrivateKey,
+    Sftp,
+}
+
+/// Every problem found while loading or validating, reported together.
+#[derive(Debug)]
+pub struct ConfigError(pub Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} configuration problem(s) found:", self.0.len())?;
+        for problem in &self.0 {
+            writeln!(f, "  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Loads the TOML file (if any) and layers the environment and `.env`
+    /// on top. An explicit `path` must exist; otherwise `VAULTSYNC_CONFIG`
+    /// and then `vaultsync.toml` are tried. Invalid environment values are
+    /// kept and reported by [`Config::validate`] with its own findings.
+    pub fn load(path: Option<&Path>) -> Result<Config, ConfigError> {
+        dotenv::dotenv().ok();
+
+        let explicit = path
+            .map(Path::to_path_buf)
+            .or_else(|| env::var("VAULTSYNC_CONFIG").ok().map(PathBuf::from));
+
+        let mut config = match explicit {
+            Some(path) => Config::from_file(&path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Config::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => Config::default(),
+        };
+
+        config.env_problems = config.apply_env();
+        config.expand_home();
+
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| ConfigError(vec![format!("Failed to read {}: {}", path.display(), e)]))?;
+        Config::from_toml_str(&text)
+            .map_err(|e| ConfigError(vec![format!("{}: {}", path.display(), e.0.join("; "))]))
+    }
+
+    pub fn from_toml_str(text: &str) -> Result<Config, ConfigError> {
+        toml::from_str(text).map_err(|e| ConfigError(vec![e.message().to_string()]))
+    }
+
+    /// Replaces a leading `~` in configured paths with the home directory,
+    /// which neither the file system nor libssh2 expand.
+    fn expand_home(&mut self) {
+        for dir in [
+            &mut self.watch_dir,
+            &mut self.encrypted_dir,
+            &mut self.decrypted_dir,
+            &mut self.state_dir,
+            &mut self.cleanup.archive_dir,
+            &mut self.cleanup.sent_dir,
+            &mut self.pgp.public_key,
+        ] {
+            expand_tilde(dir);
+        }
+        let pgp = &mut self.pgp;
+        for path in [
+            &mut pgp.private_key,
+            &mut pgp.signing_key,
+            &mut pgp.signing_key_passphrase_file,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            expand_tilde(path);
+        }
+        pgp.groups.values_mut().flatten().for_each(expand_tilde);
+        for entries in [&mut pgp.recipients, &mut pgp.trusted_signers] {
+            entries.iter_mut().for_each(expand_tilde_entry);
+        }
+
+        for sftp in std::iter::once(&mut self.sftp).chain(self.servers.values_mut()) {
+            for path in [&mut sftp.private_key, &mut sftp.known_hosts]
+                .into_iter()
+                .flatten()
+            {
+                expand_tilde(path);
+            }
+        }
+        for root in &mut self.roots {
+            expand_tilde(&mut root.dir);
+            root.encrypted_dir.iter_mut().for_each(expand_tilde);
+            root.recipients
+                .iter_mut()
+                .flatten()
+                .for_each(expand_tilde_entry);
+        }
+        for route in &mut self.routes {
+            let cleanup = &mut route.cleanup;
+            for path in [&mut cleanup.archive_dir, &mut cleanup.sent_dir]
+                .into_iter()
+                .flatten()
+            {
+                expand_tilde(path);
+            }
+            route
+                .recipients
+                .iter_mut()
+                .flatten()
+                .for_each(expand_tilde_entry);
+        }
+    }
+
+    /// Overrides fields with any of the legacy `.env` variables that are set.
+    fn apply_env(&mut self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        env_path("WATCH_DIR", &mut self.watch_dir);
+        env_path("ENCRYPTED_DIR", &mut self.encrypted_dir);
+        env_path("DECRYPTED_DIR", &mut self.decrypted_dir);
+        env_path("STATE_DIR", &mut self.state_dir);
+        env_parse("COLLISION_POLICY", &mut self.collision, &mut problems);
+        env_path("PGP_PUBLIC_KEY", &mut self.pgp.public_key);
+        env_list("PGP_RECIPIENTS", &mut self.pgp.recipients);
+        if let Ok(val) = env::var("PGP_PRIVATE_KEY") {
+            self.pgp.private_key = Some(PathBuf::from(val));
+        }
+        if let Ok(val) = env::var("PGP_PRIVATE_KEY_PASSPHRASE") {
+            self.pgp.private_key_passphrase = Some(val);
+        }
+        if let Ok(val) = env::var("PGP_SIGNING_KEY") {
+            self.pgp.signing_key = Some(PathBuf::from(val));
+        }
+        if let Ok(val) = env::var("PGP_SIGNING_KEY_PASSPHRASE") {
+            self.pgp.signing_key_passphrase = Some(val);
+        }
+        if let Ok(val) = env::var("PGP_SIGNING_KEY_PASSPHRASE_FILE") {
+            self.pgp.signing_key_passphrase_file = Some(PathBuf::from(val));
+        }
+        if let Ok(val) = env::var("PGP_SIGNING_KEY_PASSPHRASE_COMMAND") {
+            self.pgp.signing_key_passphrase_command = Some(val);
+        }
+        env_parse(
+            "PGP_SIGNING_KEY_PASSPHRASE_AGENT",
+            &mut self.pgp.signing_key_passphrase_agent,
+            &mut problems,
+        );
+        env_parse(
+            "PGP_DETACHED_SIGNATURE",
+            &mut self.pgp.detached_signature,
+            &mut problems,
+        );
+        env_parse(
+            "PGP_DETACHED_SIGNATURE_ARMOR",
+            &mut self.pgp.detached_signature_armor,
+            &mut problems,
+        );
+        env_list("PGP_TRUSTED_SIGNERS", &mut self.pgp.trusted_signers);
+
+        env_string("SFTP_HOST", &mut self.sftp.host);
+        env_parse("SFTP_PORT", &mut self.sftp.port, &mut problems);
+        env_string("SFTP_USER", &mut self.sftp.user);
+        env_parse("SFTP_AUTH", &mut self.sftp.auth, &mut problems);
+        if let Ok(val) = env::var("SFTP_PASS") {
+            self.sftp.password = Some(val);
+        }
+        if let Ok(val) = env::var("SFTP_PRIVATE_KEY") {
+            self.sftp.private_key = Some(PathBuf::from(val));
+        }
+        if let Ok(val) = env::var("SFTP_PRIVATE_KEY_PASSPHRASE") {
+            self.sftp.private_key_passphrase = Some(val);
+        }
+        if let Ok(val) = env::var("SFTP_KNOWN_HOSTS") {
+            self.sftp.known_hosts = Some(PathBuf::from(val));
+        }
+        if let Ok(val) = env::var("SFTP_HOST_KEY_FINGERPRINT") {
+            self.sftp.host_key_fingerprint = Some(val);
+        }
+        env_parse(
+            "SFTP_HOST_KEY_POLICY",
+            &mut self.sftp.host_key_policy,
+            &mut problems,
+        );
+        env_string("SFTP_REMOTE_DIR", &mut self.sftp.remote_dir);
+        env_parse(
+            "SFTP_CONNECTIONS",
+            &mut self.sftp.connections,
+            &mut problems,
+        );
+        env_parse(
+            "SFTP_BUFFER_SIZE_KB",
+            &mut self.sftp.buffer_size_kb,
+            &mut problems,
+        );
+        env_parse_opt(
+            "SFTP_BANDWIDTH_LIMIT_KBPS",
+            &mut self.sftp.bandwidth_limit_kbps,
+            &mut problems,
+        );
+        env_parse("SFTP_RESUME", &mut self.sftp.resume, &mut problems);
+        env_parse(
+            "SFTP_RESUME_VERIFY",
+            &mut self.sftp.resume_verify,
+            &mut problems,
+        );
+        env_parse("SFTP_VERIFY", &mut self.sftp.verify, &mut problems);
+        env_string("SFTP_TEMP_PREFIX", &mut self.sftp.temp_prefix);
+        env_string("SFTP_TEMP_SUFFIX", &mut self.sftp.temp_suffix);
+        env_parse("SFTP_RETRY", &mut self.sftp.retry, &mut problems);
+        env_parse(
+            "SFTP_RETRY_BACKOFF_MS",
+            &mut self.sftp.retry_backoff_ms,
+            &mut problems,
+        );
+        env_parse(
+            "SFTP_RETRY_MAX_BACKOFF_MS",
+            &mut self.sftp.retry_max_backoff_ms,
+            &mut problems,
+        );
+        env_parse(
+            "WATCH_SCAN_INTERVAL_SECS",
+            &mut self.watch.scan_interval_secs,
+            &mut problems,
+        );
+        env_parse(
+            "WATCH_QUIET_PERIOD_MS",
+            &mut self.watch.quiet_period_ms,
+            &mut problems,
+        );
+        env_parse(
+            "WATCH_CLOSE_WRITE",
+            &mut self.watch.close_write,
+            &mut problems,
+        );
+        env_parse("WORKERS", &mut self.workers.count, &mut problems);
+        env_parse(
+            "WORKER_QUEUE_CAPACITY",
+            &mut self.workers.queue_capacity,
+            &mut problems,
+        );
+        env_parse(
+            "WORKER_ENCRYPT_CONCURRENCY",
+            &mut self.workers.encrypt_concurrency,
+            &mut problems,
+        );
+        env_parse(
+            "WORKER_UPLOAD_CONCURRENCY",
+            &mut self.workers.upload_concurrency,
+            &mut problems,
+        );
+        env_list("FILTER_INCLUDE", &mut self.filter.include);
+        env_list("FILTER_EXCLUDE", &mut self.filter.exclude);
+        env_string("FILTER_IGNORE_FILE", &mut self.filter.ignore_file);
+        env_parse_opt("FILTER_MIN_SIZE", &mut self.filter.min_size, &mut problems);
+        env_parse_opt("FILTER_MAX_SIZE", &mut self.filter.max_size, &mut problems);
+        env_parse(
+            "QUEUE_RETRY_INTERVAL_SECS",
+            &mut self.queue.retry_interval_secs,
+            &mut problems,
+        );
+        env_parse(
+            "QUEUE_RETRY_MAX_INTERVAL_SECS",
+            &mut self.queue.retry_max_interval_secs,
+            &mut problems,
+        );
+        env_parse(
+            "CLEANUP_ORIGINAL",
+            &mut self.cleanup.original,
+            &mut problems,
+        );
+        env_parse(
+            "CLEANUP_SECURE_DELETE_PASSES",
+            &mut self.cleanup.secure_delete_passes,
+            &mut problems,
+        );
+        env_parse("CLEANUP_DRY_RUN", &mut self.cleanup.dry_run, &mut problems);
+        env_path("CLEANUP_ARCHIVE_DIR", &mut self.cleanup.archive_dir);
+        env_parse(
+            "CLEANUP_ARCHIVE_BY_DATE",
+            &mut self.cleanup.archive_by_date,
+            &mut problems,
+        );
+        env_parse(
+            "CLEANUP_ENCRYPTED",
+            &mut self.cleanup.encrypted,
+            &mut problems,
+        );
+        env_path("CLEANUP_SENT_DIR", &mut self.cleanup.sent_dir);
+        env_parse_opt(
+            "CLEANUP_SENT_MAX_AGE_DAYS",
+            &mut self.cleanup.sent_max_age_days,
+            &mut problems,
+        );
+        env_parse_opt(
+            "CLEANUP_SENT_MAX_FILES",
+            &mut self.cleanup.sent_max_files,
+            &mut problems,
+        );
+
+        problems
+    }
+
+    /// Every watched folder: `watch_dir`, if set, followed by each `[[root]]`.
+    pub fn watch_roots(&self) -> Vec<WatchRootConfig> {
+        let mut roots = Vec::new();
+        if !self.watch_dir.as_os_str().is_empty() {
+            roots.push(WatchRootConfig {
+                dir: self.watch_dir.clone(),
+                ..WatchRootConfig::default()
+            });
+        }
+        roots.extend(self.roots.iter().cloned());
+        roots
+    }
+
+    /// Where a watched folder's encrypted copies are written.
+    pub fn root_encrypted_dir(&self, root: &WatchRootConfig) -> PathBuf {
+        root.encrypted_dir
+            .clone()
+            .unwrap_or_else(|| self.encrypted_dir.clone())
+    }
+
+    /// The server (`None` for `[sftp]`) and remote directory a watched
+    /// folder uploads to when no route says otherwise.
+    fn root_destination<'a>(&'a self, root: &'a WatchRootConfig) -> (Option<&'a str>, &'a str) {
+        let server = root.server.as_deref();
+        let remote_dir = root.remote_dir.as_deref().unwrap_or_else(|| {
+            server
+                .and_then(|name| self.servers.get(name))
+                .unwrap_or(&self.sftp)
+                .remote_dir
+                .as_str()
+        });
+        (server, remote_dir)
+    }
+
+    /// Checks that everything the given command needs is present and sane.
+    pub fn validate(&self, needs: &[Need]) -> Result<(), ConfigError> {
+        let mut problems = self.env_problems.clone();
+
+        if needs.contains(&Need::WatchDir) {
+            if self.watch_dir.as_os_str().is_empty() && self.roots.is_empty() {
+                problems.push("watch_dir (WATCH_DIR) is not set".to_string());
+            } else if !self.watch_dir.as_os_str().is_empty() && !self.watch_dir.is_dir() {
+                problems.push(format!(
+                    "watch_dir '{}' does not exist",
+                    self.watch_dir.display()
+                ));
+            }
+            for (i, root) in self.roots.iter().enumerate() {
+                let label = format!("root[{}]", i + 1);
+                if !root.dir.is_dir() {
+                    problems.push(format!(
+                        "{}.dir '{}' does not exist",
+                        label,
+                        root.dir.display()
+                    ));
+                }
+                if let Some(server) = &root.server {
+                    if !self.servers.contains_key(server) {
+                        problems.push(format!(
+                            "{}.server '{}' is not defined under [servers]",
+                            label, server
+                        ));
+                    }
+                }
+            }
+            let roots = self.watch_roots();
+            for (i, a) in roots.iter().enumerate() {
+                for b in &roots[i + 1..] {
+                    if is_inside(&a.dir, &b.dir) || is_inside(&b.dir, &a.dir) {
+                        problems.push(format!(
+                            "watched folders '{}' and '{}' overlap",
+                            a.dir.display(),
+                            b.dir.display()
+                        ));
+                    }
+                    // The same relative path in both would map to one file.
+                    let encrypted_dir = self.root_encrypted_dir(a);
+                    if is_same_path(&encrypted_dir, &self.root_encrypted_dir(b)) {
+                        problems.push(format!(
+                            "watched folders '{}' and '{}' share encrypted_dir '{}'; give each root its own",
+                            a.dir.display(),
+                            b.dir.display(),
+                            encrypted_dir.display()
+                        ));
+                    }
+                    if self.root_destination(a) == self.root_destination(b) {
+                        problems.push(format!(
+                            "watched folders '{}' and '{}' upload to the same remote_dir '{}'; give each root its own remote_dir or server",
+                            a.dir.display(),
+                            b.dir.display(),
+                            self.root_destination(a).1
+                        ));
+                    }
+                }
+            }
+            if self.state_dir.as_os_str().is_empty() {
+                problems.push("state_dir (STATE_DIR) is not set".to_string());
+            }
+            for (value, name) in [
+                (self.workers.count, "workers.count (WORKERS)"),
+                (
+                    self.workers.queue_capacity,
+                    "workers.queue_capacity (WORKER_QUEUE_CAPACITY)",
+                ),
+                (
+                    self.workers.encrypt_concurrency,
+                    "workers.encrypt_concurrency (WORKER_ENCRYPT_CONCURRENCY)",
+                ),
+                (
+                    self.workers.upload_concurrency,
+                    "workers.upload_concurrency (WORKER_UPLOAD_CONCURRENCY)",
+                ),
+            ] {
+                if value == 0 {
+                    problems.push(format!("{} must be at least 1", name));
+                }
+            }
+            if let Err(e) = FileFilter::new(&self.watch_dir, &self.filter) {
+                problems.push(format!("filter: {}", e));
+            }
+            if let (Some(min), Some(max)) = (self.filter.min_size, self.filter.max_size) {
+                if min > max {
+                    problems.push(
+                        "filter.min_size (FILTER_MIN_SIZE) must not be greater than filter.max_size"
+                            .to_string(),
+                    );
+                }
+            }
+            if self.queue.retry_interval_secs == 0 {
+                problems.push(
+                    "queue.retry_interval_secs (QUEUE_RETRY_INTERVAL_SECS) must be at least 1"
+                        .to_string(),
+                );
+            }
+            if self.queue.retry_max_interval_secs < self.queue.retry_interval_secs {
+                problems.push(
+                    "queue.retry_max_interval_secs (QUEUE_RETRY_MAX_INTERVAL_SECS) must not be less than queue.retry_interval_secs"
+                        .to_string(),
+                );
+            }
+            check_cleanup(&self.cleanup, "cleanup", true, &roots, &mut problems);
+        }
+
+        if needs.contains(&Need::PublicKey) {
+            let pgp = &self.pgp;
+            if pgp.public_key.as_os_str().is_empty() && pgp.recipients.is_empty() {
+                problems.push(
+                    "pgp.public_key (PGP_PUBLIC_KEY) or pgp.recipients (PGP_RECIPIENTS) must be set"
+                        .to_string(),
+                );
+            } else if !pgp.public_key.as_os_str().is_empty() {
+                check_file(
+                    "pgp.public_key (PGP_PUBLIC_KEY)",
+                    &pgp.public_key,
+                    &mut problems,
+                );
+            }
+            check_recipients("pgp.recipients", &pgp.recipients, pgp, &mut problems);
+            if let Some(path) = &pgp.signing_key {
+                check_file("pgp.signing_key (PGP_SIGNING_KEY)", path, &mut problems);
+            } else if pgp.detached_signature != DetachedSignature::Off {
+                problems.push(
+                    "pgp.detached_signature (PGP_DETACHED_SIGNATURE) needs pgp.signing_key (PGP_SIGNING_KEY)"
+                        .to_string(),
+                );
+            }
+            let passphrase_sources = [
+                pgp.signing_key_passphrase.is_some(),
+                pgp.signing_key_passphrase_file.is_some(),
+                pgp.signing_key_passphrase_command.is_some(),
+                pgp.signing_key_passphrase_agent,
+            ];
+            if passphrase_sources.iter().filter(|&&set| set).count() > 1 {
+                problems.push(
+                    "only one of pgp.signing_key_passphrase, signing_key_passphrase_file, signing_key_passphrase_command and signing_key_passphrase_agent may be set"
+                        .to_string(),
+                );
+            }
+            if let Some(path) = &pgp.signing_key_passphrase_file {
+                check_file(
+                    "pgp.signing_key_passphrase_file (PGP_SIGNING_KEY_PASSPHRASE_FILE)",
+                    path,
+                    &mut problems,
+                );
+            }
+            for (i, root) in self.roots.iter().enumerate() {
+                if let Some(recipients) = &root.recipients {
+                    let label = format!("root[{}].recipients", i + 1);
+                    check_recipients(&label, recipients, pgp, &mut problems);
+                }
+            }
+            for (name, members) in &pgp.groups {
+                for path in members.iter().filter(|path| !path.exists()) {
+                    problems.push(format!(
+                        "pgp.groups.{} entry '{}' does not exist",
+                        name,
+                        path.display()
+                    ));
+                }
+            }
+        }
+
+        if needs.contains(&Need::PrivateKey) {
+            match &self.pgp.private_key {
+                Some(path) => check_file("pgp.private_key (PGP_PRIVATE_KEY)", path, &mut problems),
+                None => problems.push("pgp.private_key (PGP_PRIVATE_KEY) is not set".to_string()),
+            }
+        }
+
+        if needs.contains(&Need::Sftp) {
+            check_sftp(&self.sftp, "sftp", true, &mut problems);
+            for (name, server) in &self.servers {
+                check_sftp(server, &format!("servers.{}", name), false, &mut problems);
+            }
+        }
+
+        if needs.contains(&Need::WatchDir) {
+            let roots = self.watch_roots();
+            for (i, route) in self.routes.iter().enumerate() {
+                let label = format!("route[{}]", i + 1);
+                match Rule::parse(&route.pattern) {
+                    Ok(Some(_)) => {}
+                    Ok(None) => problems.push(format!("{}.match must not be empty", label)),
+                    Err(e) => problems.push(format!("{}.match: {}", label, e)),
+                }
+                if let Some(server) = &route.server {
+                    if !self.servers.contains_key(server) {
+                        problems.push(format!(
+                            "{}.server '{}' is not defined under [servers]",
+                            label, server
+                        ));
+                    }
+                }
+                if let Some(recipients) = &route.recipients {
+                    if recipients.is_empty() {
+                        problems.push(format!("{}.recipients must not be empty", label));
+                    }
+                    let label = format!("{}.recipients", label);
+                    check_recipients(&label, recipients, &self.pgp, &mut problems);
+                }
+                let cleanup = route.cleanup.over(&self.cleanup);
+                let label = format!("{}.cleanup", label);
+                check_cleanup(&cleanup, &label, false, &roots, &mut problems);
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError(problems))
+        }
+    }
+}
+
+fn expand_tilde(path: &mut PathBuf) {
+    let Ok(rest) = path.strip_prefix("~") else {
+        return;
+    };
+    if let Some(home) = dirs::home_dir() {
+        *path = home.join(rest);
+    }
+}
+
+/// Recipient entries are group names or paths; only paths start with `~/`.
+fn expand_tilde_entry(entry: &mut String) {
+    if entry == "~" || entry.starts_with("~/") {
+        let mut path = PathBuf::from(entry.as_str());
+        expand_tilde(&mut path);
+        *entry = path.to_string_lossy().into_owned();
+    }
+}
+
+fn env_path(name: &str, target: &mut PathBuf) {
+    if let Ok(val) = env::var(name) {
+        *target = PathBuf::from(val);
+    }
+}
+
+fn env_string(name: &str, target: &mut String) {
+    if let Ok(val) = env::var(name) {
+        *target = val;
+    }
+}
+
+fn env_parse<T: FromStr>(name: &str, target: &mut T, problems: &mut Vec<String>) {
+    if let Ok(val) = env::var(name) {
+        match val.parse() {
+            Ok(parsed) => *target = parsed,
+            Err(_) => problems.push(format!("{} has an invalid value '{}'", name, val)),
+        }
+    }
+}
+
+fn env_parse_opt<T: FromStr>(name: &str, target: &mut Option<T>, problems: &mut Vec<String>) {
+    if let Ok(val) = env::var(name) {
+        match val.parse() {
+            Ok(parsed) => *target = Some(parsed),
+            Err(_) => problems.push(format!("{} has an invalid value '{}'", name, val)),
+        }
+    }
+}
+
+/// Comma-separated list; an empty value clears the list.
+fn env_list(name: &str, target: &mut Vec<String>) {
+    if let Ok(val) = env::var(name) {
+        *target = val
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(String::from)
+            .collect();
+    }
+}
+
+/// Checks one server's settings. `section` prefixes the setting names in
+/// the messages; `from_env` adds the environment variable that sets each.
+fn check_sftp(sftp: &SftpConfig, section: &str, from_env: bool, problems: &mut Vec<String>) {
+    let name = |field: &str, env: &str| {
+        if from_env && !env.is_empty() {
+            format!("{}.{} ({})", section, field, env)
+        } else {
+            format!("{}.{}", section, field)
+        }
+    };
+
+    if sftp.host.is_empty() {
+        problems.push(format!("{} is not set", name("host", "SFTP_HOST")));
+    }
+    if sftp.port == 0 {
+        problems.push(format!("{} must not be 0", name("port", "SFTP_PORT")));
+    }
+    if sftp.user.is_empty() {
+        problems.push(format!("{} is not set", name("user", "SFTP_USER")));
+    }
+    match sftp.auth {
+        SftpAuth::Password | SftpAuth::KeyboardInteractive => {
+            if sftp.password.is_none() {
+                problems.push(format!("{} is not set", name("password", "SFTP_PASS")));
+            }
+        }
+        SftpAuth::Key => match &sftp.private_key {
+            Some(path) => check_file(&name("private_key", "SFTP_PRIVATE_KEY"), path, problems),
+            None => problems.push(format!(
+                "{} is not set",
+                name("private_key", "SFTP_PRIVATE_KEY")
+            )),
+        },
+        SftpAuth::Agent => {}
+    }
+    if sftp.remote_dir.is_empty() {
+        problems.push(format!(
+            "{} is not set",
+            name("remote_dir", "SFTP_REMOTE_DIR")
+        ));
+    }
+    if let Some(fingerprint) = &sftp.host_key_fingerprint {
+        if !fingerprint.starts_with("SHA256:") {
+            problems.push(format!(
+                "{} '{}' must be an OpenSSH SHA256:... fingerprint",
+                name("host_key_fingerprint", ""),
+                fingerprint
+            ));
+        }
+    }
+    if sftp.connections == 0 {
+        problems.push(format!(
+            "{} must be at least 1",
+            name("connections", "SFTP_CONNECTIONS")
+        ));
+    }
+    if sftp.buffer_size_kb == 0 {
+        problems.push(format!(
+            "{} must be at least 1",
+            name("buffer_size_kb", "SFTP_BUFFER_SIZE_KB")
+        ));
+    }
+    if sftp.bandwidth_limit_kbps == Some(0) {
+        problems.push(format!(
+            "{} must be at least 1",
+            name("bandwidth_limit_kbps", "SFTP_BANDWIDTH_LIMIT_KBPS")
+        ));
+    }
+    if sftp.temp_prefix.is_empty() && sftp.temp_suffix.is_empty() {
+        problems.push(format!(
+            "{} and {} must not both be empty",
+            name("temp_prefix", ""),
+            name("temp_suffix", "")
+        ));
+    }
+    if sftp.temp_prefix.contains('/') || sftp.temp_suffix.contains('/') {
+        problems.push(format!(
+            "{} and {} must not contain '/'",
+            name("temp_prefix", ""),
+            name("temp_suffix", "")
+        ));
+    }
+    if sftp.retry == 0 {
+        problems.push(format!(
+            "{} must be at least 1",
+            name("retry", "SFTP_RETRY")
+        ));
+    }
+    if sftp.retry_max_backoff_ms < sftp.retry_backoff_ms {
+        problems.push(format!(
+            "{} must not be less than {}",
+            name("retry_max_backoff_ms", "SFTP_RETRY_MAX_BACKOFF_MS"),
+            name("retry_backoff_ms", "")
+        ));
+    }
+}
+
+/// Checks that every recipient entry names a group or an existing path.
+fn check_recipients(label: &str, entries: &[String], pgp: &PgpConfig, problems: &mut Vec<String>) {
+    for entry in entries {
+        if !pgp.groups.contains_key(entry) && !Path::new(entry).exists() {
+            problems.push(format!(
+                "{} entry '{}' is neither a group nor an existing path",
+                label, entry
+            ));
+        }
+    }
+}
+
+/// Checks one cleanup policy; `section` and `from_env` as for [`check_sftp`].
+fn check_cleanup(
+    cleanup: &CleanupConfig,
+    section: &str,
+    from_env: bool,
+    roots: &[WatchRootConfig],
+    problems: &mut Vec<String>,
+) {
+    let name = |field: &str, env: &str| {
+        if from_env {
+            format!("{}.{} ({})", section, field, env)
+        } else {
+            format!("{}.{}", section, field)
+        }
+    };
+
+    if cleanup.original == OriginalAction::Archive {
+        if cleanup.archive_dir.as_os_str().is_empty() {
+            problems.push(format!(
+                "{} is not set",
+                name("archive_dir", "CLEANUP_ARCHIVE_DIR")
+            ));
+        } else if roots
+            .iter()
+            .any(|root| is_inside(&cleanup.archive_dir, &root.dir))
+        {
+            problems.push(format!(
+                "{} must not be inside a watched folder",
+                name("archive_dir", "CLEANUP_ARCHIVE_DIR")
+            ));
+        }
+    }
+    if cleanup.encrypted == EncryptedAction::Sent && cleanup.sent_dir.as_os_str().is_empty() {
+        problems.push(format!(
+            "{} is not set",
+            name("sent_dir", "CLEANUP_SENT_DIR")
+        ));
+    }
+    if cleanup.sent_max_files == Some(0) {
+        problems.push(format!(
+            "{} must be at least 1",
+            name("sent_max_files", "CLEANUP_SENT_MAX_FILES")
+        ));
+    }
+}
+
+/// Whether `path` is `dir` or below it, comparing absolute paths.
+fn is_inside(path: &Path, dir: &Path) -> bool {
+    match (std::path::absolute(path), std::path::absolute(dir)) {
+        (Ok(path), Ok(dir)) => path.starts_with(dir),
+        _ => false,
+    }
+}
+
+fn is_same_path(a: &Path, b: &Path) -> bool {
+    is_inside(a, b) && is_inside(b, a)
+}
+
+fn check_file(label: &str, path: &Path, problems: &mut Vec<String>) {
+    if path.as_os_str().is_empty() {
+        problems.push(format!("{} is not set", label));
+    } else if !path.is_file() {
+        problems.push(format!("{} '{}' does not exist", label, path.display()));
+    }
 }
+
 pub fn load_encryption_key() -> Key<Aes256Gcm> {
     let key_b64 = env::var("ENCRYPTION_KEY").expect("ENCRYPTION_KEY not set in .env");
     let key_bytes = general_purpose::STANDARD
@@ -26,33 +1445,7 @@ pub fn load_encryption_key() -> Key<Aes256Gcm> {
     let key = Key::<Aes256Gcm>::from_slice(&key_bytes);
     Key::<Aes256Gcm>::clone_from_slice(key)
 }
-pub fn load_sftp_retry_config() -> (u32, u64) {
-    let retry_count = env::var("SFTP_RETRY")
-        .ok()
-        .and_then(|s| s.parse::<u32>().ok())
-        .unwrap_or(3);
-
-    let backoff_ms = env::var("SFTP_RETRY_BACKOFF_MS")
-        .ok()
-        .and_then(|s| s.parse::<u64>().ok())
-        .unwrap_or(1000);
-
-    (retry_count, backoff_ms)
-}
-pub fn encrypted_output_dir() -> PathBuf {
-    env::var("ENCRYPTED_DIR")
-        .map(PathBuf::from)
-        .unwrap_or_else(|_| PathBuf::from("encrypted"))
-}
-pub fn decrypted_output_dir() -> PathBuf {
-    env::var("DECRYPTED_DIR")
-        .map(PathBuf::from)
-        .unwrap_or_else(|_| PathBuf::from("decrypted"))
-}
 
-pub fn pgp_public_key_path() -> String {
-    env::var("PGP_PUBLIC_KEY").expect("PGP_PUBLIC_KEY must be set in .env")
-}
 #[cfg(target_os = "windows")]
 pub fn setup_autostart() {
     setup_autostart_windows();
@@ -121,19 +1514,317 @@ fn setup_autostart_linux() {
 #[cfg(test)]
 mod tests {
     use std::env;
-    use std::path::PathBuf;
+    use std::path::{Path, PathBuf};
 
-    use crate::config::{decrypted_output_dir, encrypted_output_dir};
+    use crate::config::{Config, HostKeyPolicy, Need, OriginalAction, ResumeVerify, SftpAuth};
 
     #[test]
     fn test_encrypted_output_dir_env_override() {
         env::set_var("ENCRYPTED_DIR", "encrypted");
-        assert_eq!(encrypted_output_dir(), PathBuf::from("encrypted"));
+        let mut config = Config::from_toml_str("encrypted_dir = \"from-file\"").unwrap();
+        config.apply_env();
+        assert_eq!(config.encrypted_dir, PathBuf::from("encrypted"));
     }
 
     #[test]
     fn test_decrypted_output_dir_env_override() {
         env::set_var("DECRYPTED_DIR", "decrypted");
-        assert_eq!(decrypted_output_dir(), PathBuf::from("decrypted"));
+        let mut config = Config::from_toml_str("decrypted_dir = \"from-file\"").unwrap();
+        config.apply_env();
+        assert_eq!(config.decrypted_dir, PathBuf::from("decrypted"));
+    }
+
+    #[test]
+    fn test_env_problems_are_reported_with_the_rest() {
+        env::set_var("QUEUE_RETRY_INTERVAL_SECS", "soon");
+        let mut config = Config::default();
+        config.env_problems = config.apply_env();
+        env::remove_var("QUEUE_RETRY_INTERVAL_SECS");
+
+        let err = config.validate(&[Need::WatchDir]).unwrap_err();
+        assert!(err
+            .0
+            .contains(&"QUEUE_RETRY_INTERVAL_SECS has an invalid value 'soon'".to_string()));
+        assert!(err
+            .0
+            .contains(&"watch_dir (WATCH_DIR) is not set".to_string()));
+    }
+
+    #[test]
+    fn test_toml_sections_and_defaults() {
+        let config = Config::from_toml_str(
+            r#"
+            watch_dir = "inbox"
+
+            [sftp]
+            host = "sftp.example.com"
+            user = "vault"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.watch_dir, PathBuf::from("inbox"));
+        assert_eq!(config.sftp.host, "sftp.example.com");
+        assert_eq!(config.sftp.port, 22);
+        assert_eq!(config.sftp.retry, 3);
+        assert_eq!(config.state_dir, PathBuf::from("state"));
+        assert_eq!(config.queue.retry_interval_secs, 300);
+    }
+
+    #[test]
+    fn test_sftp_auth_methods() {
+        let config = Config::from_toml_str(
+            r#"
+            [sftp]
+            auth = "keyboard-interactive"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.sftp.auth, SftpAuth::KeyboardInteractive);
+        assert_eq!("key".parse::<SftpAuth>(), Ok(SftpAuth::Key));
+        assert!("kerberos".parse::<SftpAuth>().is_err());
+    }
+
+    #[test]
+    fn test_resume_always_checks_contents() {
+        let config = Config::from_toml_str("[sftp]\nresume_verify = \"sha256\"\n").unwrap();
+        assert_eq!(config.sftp.resume_verify, ResumeVerify::ReadBack);
+        assert_eq!(Config::default().sftp.resume_verify, ResumeVerify::ReadBack);
+        assert_eq!(
+            "sha256sum".parse::<ResumeVerify>(),
+            Ok(ResumeVerify::Sha256sum)
+        );
+        assert!("size".parse::<ResumeVerify>().is_err());
+    }
+
+    #[test]
+    fn test_key_auth_requires_private_key_not_password() {
+        let mut config = Config::default();
+        config.sftp.auth = SftpAuth::Key;
+        let err = config.validate(&[Need::Sftp]).unwrap_err();
+
+        assert!(err.0.iter().any(|p| p.contains("SFTP_PRIVATE_KEY")));
+        assert!(!err.0.iter().any(|p| p.contains("SFTP_PASS")));
+    }
+
+    #[test]
+    fn test_host_key_policy_defaults_to_accept_new() {
+        let config = Config::from_toml_str("[sftp]\nhost = \"h\"").unwrap();
+        assert_eq!(config.sftp.host_key_policy, HostKeyPolicy::AcceptNew);
+
+        let config = Config::from_toml_str("[sftp]\nhost_key_policy = \"strict\"").unwrap();
+        assert_eq!(config.sftp.host_key_policy, HostKeyPolicy::Strict);
+    }
+
+    #[test]
+    fn test_unknown_key_is_rejected() {
+        assert!(Config::from_toml_str("wach_dir = \"typo\"").is_err());
+    }
+
+    #[test]
+    fn test_validate_reports_all_problems() {
+        let config = Config::default();
+        let err = config
+            .validate(&[Need::WatchDir, Need::PublicKey, Need::Sftp])
+            .unwrap_err();
+
+        assert!(
+            err.0.len() >= 6,
+            "expected every missing setting, got {:?}",
+            err.0
+        );
+    }
+
+    #[test]
+    fn test_home_is_expanded_in_paths() {
+        let home = dirs::home_dir().unwrap();
+        let mut config = Config::from_toml_str(
+            r#"
+            [pgp]
+            recipients = ["escrow", "~/keys/customer.asc"]
+
+            [servers.acme]
+            private_key = "~/.ssh/id_ed25519"
+            known_hosts = "~other/known_hosts"
+            "#,
+        )
+        .unwrap();
+        config.expand_home();
+
+        assert_eq!(
+            config.servers["acme"].private_key,
+            Some(home.join(".ssh/id_ed25519"))
+        );
+        assert_eq!(
+            config.servers["acme"].known_hosts,
+            Some(PathBuf::from("~other/known_hosts"))
+        );
+        assert_eq!(
+            config.pgp.recipients,
+            vec![
+                "escrow".to_string(),
+                home.join("keys/customer.asc")
+                    .to_string_lossy()
+                    .into_owned()
+            ]
+        );
+        assert_eq!(config.encrypted_dir, PathBuf::from("encrypted"));
+    }
+
+    #[test]
+    fn test_routes_and_servers() {
+        let config = Config::from_toml_str(
+            r#"
+            watch_dir = "."
+
+            [servers.acme]
+            host = "sftp.acme.example"
+            user = "vault"
+            remote_dir = "/incoming"
+
+            [[route]]
+            match = "clients/acme/**"
+            server = "acme"
+            cleanup = { original = "keep" }
+
+            [[route]]
+            match = "clients/globex/"
+            server = "globex"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.routes.len(), 2);
+        assert_eq!(config.routes[0].pattern, "clients/acme/**");
+        assert_eq!(
+            config.routes[0].cleanup.original,
+            Some(OriginalAction::Keep)
+        );
+        assert_eq!(config.servers["acme"].port, 22);
+
+        let err = config.validate(&[Need::WatchDir, Need::Sftp]).unwrap_err();
+        assert!(err
+            .0
+            .contains(&"route[2].server 'globex' is not defined under [servers]".to_string()));
+        assert!(err
+            .0
+            .contains(&"servers.acme.password is not set".to_string()));
+    }
+
+    /// Config with a `watch_dir` and `[[root]]`s below a temporary folder.
+    fn with_roots(dir: &Path, roots: &str) -> Config {
+        for name in ["scans", "contracts", "scans/inner"] {
+            std::fs::create_dir_all(dir.join(name)).unwrap();
+        }
+        let toml = format!(
+            "watch_dir = '{}'\nencrypted_dir = '{}'\n\n[sftp]\nremote_dir = '/upload'\n\n{}",
+            dir.join("scans").display(),
+            dir.join("encrypted").display(),
+            roots.replace("$DIR", &dir.display().to_string())
+        );
+        Config::from_toml_str(&toml).unwrap()
+    }
+
+    fn watch_problems(config: &Config) -> Vec<String> {
+        config
+            .validate(&[Need::WatchDir])
+            .err()
+            .map(|e| e.0)
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn test_roots_with_their_own_overrides() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = with_roots(
+            dir.path(),
+            r#"
+            [servers.legal]
+            remote_dir = "/legal"
+
+            [[root]]
+            dir = "$DIR/contracts"
+            encrypted_dir = "$DIR/encrypted-contracts"
+            recipients = ["legal.asc"]
+            server = "legal"
+            remote_dir = "/legal/contracts"
+            "#,
+        );
+
+        let roots = config.watch_roots();
+        assert_eq!(roots.len(), 2);
+        assert_eq!(roots[0].dir, dir.path().join("scans"));
+        assert_eq!(roots[0].recipients, None);
+        assert_eq!(roots[1].recipients, Some(vec!["legal.asc".to_string()]));
+        assert_eq!(roots[1].server.as_deref(), Some("legal"));
+        assert_eq!(
+            config.root_encrypted_dir(&roots[0]),
+            dir.path().join("encrypted")
+        );
+        assert_eq!(
+            config.root_encrypted_dir(&roots[1]),
+            dir.path().join("encrypted-contracts")
+        );
+        assert_eq!(watch_problems(&config), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_roots_must_not_share_outputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = with_roots(dir.path(), "[[root]]\ndir = '$DIR/contracts'\n");
+        let problems = watch_problems(&config);
+        assert!(problems.iter().any(|p| p.contains("share encrypted_dir")));
+        assert!(problems
+            .iter()
+            .any(|p| p.contains("upload to the same remote_dir '/upload'")));
+
+        // A different remote_dir on the same server is enough remotely.
+        let config = with_roots(
+            dir.path(),
+            "[[root]]\ndir = '$DIR/contracts'\nremote_dir = '/contracts'\n",
+        );
+        let problems = watch_problems(&config);
+        assert!(problems.iter().any(|p| p.contains("share encrypted_dir")));
+        assert!(!problems.iter().any(|p| p.contains("remote_dir")));
+    }
+
+    #[test]
+    fn test_roots_must_not_overlap() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = with_roots(
+            dir.path(),
+            r#"
+            [[root]]
+            dir = "$DIR/scans/inner"
+            encrypted_dir = "$DIR/encrypted-inner"
+            remote_dir = "/inner"
+            "#,
+        );
+        let problems = watch_problems(&config);
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].ends_with("overlap"));
+    }
+
+    #[test]
+    fn test_archive_dir_outside_every_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let roots = r#"
+            [cleanup]
+            original = "archive"
+            archive_dir = "$DIR/contracts/archive"
+
+            [[root]]
+            dir = "$DIR/contracts"
+            encrypted_dir = "$DIR/encrypted-contracts"
+            remote_dir = "/contracts"
+            "#;
+        let config = with_roots(dir.path(), roots);
+        assert_eq!(
+            watch_problems(&config),
+            vec!["cleanup.archive_dir (CLEANUP_ARCHIVE_DIR) must not be inside a watched folder"]
+        );
+
+        let config = with_roots(dir.path(), &roots.replace("contracts/archive", "archive"));
+        assert_eq!(watch_problems(&config), Vec::<String>::new());
     }
 }
@@ -0,0 +1,120 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Holds back paths until they have stopped changing, so a file that is
+/// still being copied into the watch folder is not picked up half-written.
+pub struct Debouncer {
+    quiet_period: Duration,
+    pending: HashMap<PathBuf, Pending>,
+}
+
+struct Pending {
+    size: u64,
+    modified: Option<SystemTime>,
+    stable_since: Instant,
+}
+
+impl Debouncer {
+    pub fn new(quiet_period: Duration) -> Self {
+        Debouncer {
+            quiet_period,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Starts, or restarts, the quiet period for `path`.
+    pub fn touch(&mut self, path: &Path, now: Instant) {
+        let Ok(metadata) = fs::metadata(path) else {
+            return;
+        };
+        self.pending.insert(
+            path.to_path_buf(),
+            Pending {
+                size: metadata.len(),
+                modified: metadata.modified().ok(),
+                stable_since: now,
+            },
+        );
+    }
+
+    pub fn forget(&mut self, path: &Path) {
+        self.pending.remove(path);
+    }
+
+    /// Removes and returns the paths whose size and modification time have
+    /// not changed for the quiet period. Paths that no longer exist are
+    /// dropped.
+    pub fn take_stable(&mut self, now: Instant) -> Vec<PathBuf> {
+        let mut stable = Vec::new();
+
+        self.pending.retain(|path, pending| {
+            let Ok(metadata) = fs::metadata(path) else {
+                return false;
+            };
+            let modified = metadata.modified().ok();
+            if metadata.len() != pending.size || modified != pending.modified {
+                pending.size = metadata.len();
+                pending.modified = modified;
+                pending.stable_since = now;
+                return true;
+            }
+            if now.duration_since(pending.stable_since) >= self.quiet_period {
+                stable.push(path.clone());
+                return false;
+            }
+            true
+        });
+
+        stable
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_is_released_after_quiet_period() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("upload.bin");
+        fs::write(&path, b"partial").unwrap();
+
+        let quiet = Duration::from_secs(2);
+        let start = Instant::now();
+        let mut debouncer = Debouncer::new(quiet);
+        debouncer.touch(&path, start);
+
+        assert!(debouncer
+            .take_stable(start + Duration::from_secs(1))
+            .is_empty());
+
+        // The writer appends more data: the quiet period starts over.
+        fs::write(&path, b"partial and the rest").unwrap();
+        let grown = start + Duration::from_secs(1);
+        assert!(debouncer.take_stable(grown).is_empty());
+        assert!(debouncer
+            .take_stable(grown + Duration::from_secs(1))
+            .is_empty());
+
+        assert_eq!(debouncer.take_stable(grown + quiet), vec![path]);
+        assert!(debouncer.take_stable(grown + quiet * 2).is_empty());
+    }
+
+    #[test]
+    fn test_deleted_path_is_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("gone.txt");
+        fs::write(&path, b"x").unwrap();
+
+        let now = Instant::now();
+        let mut debouncer = Debouncer::new(Duration::ZERO);
+        debouncer.touch(&path, now);
+        fs::remove_file(&path).unwrap();
+
+        assert!(debouncer.take_stable(now).is_empty());
+    }
+}
@@ -0,0 +1,310 @@
+use crate::config::FilterConfig;
+use glob::{MatchOptions, Pattern};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// One line of a gitignore-style pattern list.
+#[derive(Debug)]
+pub struct Rule {
+    pattern: Pattern,
+    negated: bool,
+    /// Trailing `/`: only matches directories.
+    dir_only: bool,
+    /// Contains a `/`: matched against the whole relative path instead of
+    /// any single name.
+    anchored: bool,
+}
+
+impl Rule {
+    /// Parses one line; blank lines and comments give `None`.
+    pub fn parse(line: &str) -> Result<Option<Rule>, String> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let anchored = line.contains('/');
+        let line = line.strip_prefix('/').unwrap_or(line);
+
+        let pattern =
+            Pattern::new(line).map_err(|e| format!("invalid pattern '{}': {}", line, e))?;
+        Ok(Some(Rule {
+            pattern,
+            negated,
+            dir_only,
+            anchored,
+        }))
+    }
+
+    pub fn matches(&self, relative: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            self.pattern.matches_path_with(relative, MATCH_OPTIONS)
+        } else {
+            relative
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| self.pattern.matches_with(name, MATCH_OPTIONS))
+        }
+    }
+}
+
+/// Ordered rules where, as in `.gitignore`, the last matching rule wins.
+#[derive(Debug, Default)]
+struct RuleSet(Vec<Rule>);
+
+impl RuleSet {
+    fn parse<'a>(lines: impl IntoIterator<Item = &'a str>) -> Result<RuleSet, String> {
+        let mut rules = Vec::new();
+        for line in lines {
+            rules.extend(Rule::parse(line)?);
+        }
+        Ok(RuleSet(rules))
+    }
+
+    /// `Some(true)` if the last matching rule excludes `relative`,
+    /// `Some(false)` if it re-includes it, `None` if no rule matches.
+    fn verdict(&self, relative: &Path, is_dir: bool) -> Option<bool> {
+        self.0
+            .iter()
+            .rev()
+            .find(|rule| rule.matches(relative, is_dir))
+            .map(|rule| !rule.negated)
+    }
+}
+
+/// Decides which files under the watch folder are picked up, from the
+/// configured include/exclude globs, `.vaultsyncignore` files anywhere in
+/// the tree and the size limits.
+pub struct FileFilter {
+    root: PathBuf,
+    include: RuleSet,
+    exclude: RuleSet,
+    ignore_file: String,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    /// Parsed ignore files by directory, reloaded when their mtime changes.
+    ignore_cache: Mutex<HashMap<PathBuf, (SystemTime, RuleSet)>>,
+}
+
+impl FileFilter {
+    pub fn new(root: &Path, config: &FilterConfig) -> Result<Self, String> {
+        Ok(FileFilter {
+            root: std::path::absolute(root).unwrap_or_else(|_| root.to_path_buf()),
+            include: RuleSet::parse(config.include.iter().map(String::as_str))?,
+            exclude: RuleSet::parse(config.exclude.iter().map(String::as_str))?,
+            ignore_file: config.ignore_file.clone(),
+            min_size: config.min_size,
+            max_size: config.max_size,
+            ignore_cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Checks the name rules only, for files that may still be growing.
+    pub fn matches_path(&self, path: &Path) -> bool {
+        let Ok(relative) = path.strip_prefix(&self.root) else {
+            return false;
+        };
+        if !self.ignore_file.is_empty() && relative.ends_with(&self.ignore_file) {
+            return false;
+        }
+        if !self.include.0.is_empty() && self.include.verdict(relative, false) != Some(true) {
+            return false;
+        }
+
+        // A file inside an excluded directory stays excluded, whatever the
+        // rules say about the file itself.
+        let mut ancestors: Vec<&Path> = relative.ancestors().skip(1).collect();
+        ancestors.pop(); // the empty root
+        ancestors.reverse();
+        for dir in ancestors {
+            if self.is_excluded(dir, true) {
+                return false;
+            }
+        }
+        !self.is_excluded(relative, false)
+    }
+
+    /// Full check before a file is queued: the name rules, plus it must be a
+    /// regular file (not a symlink, FIFO, socket or device) within the size
+    /// limits.
+    pub fn accepts(&self, path: &Path) -> bool {
+        let Ok(metadata) = fs::symlink_metadata(path) else {
+            return false;
+        };
+        if !metadata.file_type().is_file() {
+            return false;
+        }
+        let size = metadata.len();
+        if self.min_size.is_some_and(|min| size < min)
+            || self.max_size.is_some_and(|max| size > max)
+        {
+            println!(
+                "Skipping {}: size {} is outside the configured limits",
+                path.display(),
+                size
+            );
+            return false;
+        }
+        self.matches_path(path)
+    }
+
+    /// Applies the configured excludes, then every ignore file from the
+    /// root down to the entry's directory, each relative to its own
+    /// directory. Later rules override earlier ones.
+    fn is_excluded(&self, relative: &Path, is_dir: bool) -> bool {
+        let mut excluded = self.exclude.verdict(relative, is_dir).unwrap_or(false);
+        if self.ignore_file.is_empty() {
+            return excluded;
+        }
+
+        let mut dir = PathBuf::new();
+        let mut components = relative.components().peekable();
+        loop {
+            let rest = relative.strip_prefix(&dir).unwrap_or(relative);
+            if let Some(verdict) = self.with_ignore_file(&dir, |rules| rules.verdict(rest, is_dir))
+            {
+                excluded = verdict;
+            }
+            match components.next() {
+                Some(component) if components.peek().is_some() => dir.push(component),
+                _ => break,
+            }
+        }
+        excluded
+    }
+
+    fn with_ignore_file<T>(&self, dir: &Path, f: impl FnOnce(&RuleSet) -> Option<T>) -> Option<T> {
+        let path = self.root.join(dir).join(&self.ignore_file);
+        let modified = fs::metadata(&path).and_then(|m| m.modified()).ok()?;
+
+        let mut cache = self.ignore_cache.lock().unwrap();
+        let stale = cache.get(dir).is_none_or(|(cached, _)| *cached != modified);
+        if stale {
+            let contents = fs::read_to_string(&path).unwrap_or_default();
+            let rules = RuleSet::parse(contents.lines()).unwrap_or_else(|e| {
+                eprintln!("Ignoring {}: {}", path.display(), e);
+                RuleSet::default()
+            });
+            cache.insert(dir.to_path_buf(), (modified, rules));
+        }
+        f(&cache[dir].1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(root: &Path, include: &[&str], exclude: &[&str]) -> FileFilter {
+        let config = FilterConfig {
+            include: include.iter().map(|s| s.to_string()).collect(),
+            exclude: exclude.iter().map(|s| s.to_string()).collect(),
+            ..FilterConfig::default()
+        };
+        FileFilter::new(root, &config).unwrap()
+    }
+
+    #[test]
+    fn test_default_excludes_skip_temp_and_hidden_files() {
+        let root = Path::new("/watch");
+        let filter = FileFilter::new(root, &FilterConfig::default()).unwrap();
+
+        assert!(filter.matches_path(&root.join("report.csv")));
+        assert!(filter.matches_path(&root.join("clients/acme/report.csv")));
+        assert!(!filter.matches_path(&root.join(".report.csv.swp")));
+        assert!(!filter.matches_path(&root.join("~$budget.xlsx")));
+        assert!(!filter.matches_path(&root.join("movie.mkv.part")));
+        assert!(!filter.matches_path(&root.join(".git/config")));
+        assert!(!filter.matches_path(Path::new("/elsewhere/report.csv")));
+    }
+
+    #[test]
+    fn test_gitignore_style_rules() {
+        let root = Path::new("/watch");
+        let filter = filter(
+            root,
+            &[],
+            &["*.log", "!keep.log", "/drafts/", "build/**/*.o"],
+        );
+
+        assert!(!filter.matches_path(&root.join("a/b/debug.log")));
+        assert!(filter.matches_path(&root.join("a/keep.log")));
+        assert!(!filter.matches_path(&root.join("drafts/letter.txt")));
+        assert!(filter.matches_path(&root.join("a/drafts/letter.txt")));
+        assert!(!filter.matches_path(&root.join("build/x/y/main.o")));
+    }
+
+    #[test]
+    fn test_include_limits_to_matching_files() {
+        let root = Path::new("/watch");
+        let filter = filter(root, &["*.pdf", "clients/**"], &[]);
+
+        assert!(filter.matches_path(&root.join("scan.pdf")));
+        assert!(filter.matches_path(&root.join("clients/acme/data.csv")));
+        assert!(!filter.matches_path(&root.join("notes.txt")));
+    }
+
+    #[test]
+    fn test_ignore_file_in_subdirectory() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("clients/acme")).unwrap();
+        fs::write(
+            root.join("clients/.vaultsyncignore"),
+            "*.tmp\nacme/private/\n",
+        )
+        .unwrap();
+        let filter = filter(root, &[], &[]);
+
+        assert!(!filter.matches_path(&root.join("clients/acme/upload.tmp")));
+        assert!(!filter.matches_path(&root.join("clients/acme/private/a.txt")));
+        assert!(filter.matches_path(&root.join("clients/acme/a.txt")));
+        assert!(filter.matches_path(&root.join("upload.tmp")));
+        assert!(!filter.matches_path(&root.join("clients/.vaultsyncignore")));
+    }
+
+    #[test]
+    fn test_accepts_checks_size_and_file_type() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::write(root.join("small.txt"), "x").unwrap();
+        fs::write(root.join("big.txt"), "x".repeat(100)).unwrap();
+        let config = FilterConfig {
+            min_size: Some(2),
+            ..FilterConfig::default()
+        };
+        let filter = FileFilter::new(root, &config).unwrap();
+
+        assert!(!filter.accepts(&root.join("small.txt")));
+        assert!(filter.accepts(&root.join("big.txt")));
+        assert!(!filter.accepts(root));
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(root.join("big.txt"), root.join("link.txt")).unwrap();
+            assert!(!filter.accepts(&root.join("link.txt")));
+        }
+    }
+}
@@ -1,29 +1,101 @@
-use config::{load_watch_dir, pgp_public_key_path};
-use pgp::load_public_key;
+use clap::Parser;
+use cli::{Cli, Command};
+use collision::resolve_name;
+use config::{Config, Need};
+use pgp::{
+    decrypt_file_with_pgp, encrypt_file_to, load_default_recipients, load_recipients,
+    load_secret_key, load_signing_key, signature_path, verify_detached, write_detached_signature,
+};
+use routing::Servers;
+use sequoia_openpgp::{
+    cert::CertBuilder,
+    serialize::{Serialize, SerializeInto},
+};
+use sftp::{upload_file_with_retry, Destination, SftpPool};
 use std::{
-    path::Path,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
 };
-use watcher::start_watching;
+use watcher::{start_watching, Root};
 
+mod agent;
+mod cleanup;
+mod cli;
+mod collision;
 mod config;
+mod debounce;
+mod filter;
 mod pgp;
+mod queue;
+mod retry;
+mod routing;
+mod semaphore;
 mod sftp;
+mod transfer;
 mod watcher;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    config::setup_autostart();
-    let key_path = pgp_public_key_path();
-    let cert = load_public_key(&key_path)?;
+    let cli = Cli::parse();
+
+    let command = cli.command.unwrap_or(Command::Watch);
+    let config = match Config::load(cli.config.as_deref()).and_then(|mut config| {
+        cli.overrides.apply_to(&mut config);
+        config.validate(needs(&command))?;
+        Ok(config)
+    }) {
+        Ok(config) => config,
+        Err(e) => {
+            eprint!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    match command {
+        Command::Watch => run_watch(config),
+        Command::Encrypt { files } => run_encrypt(&config, &files),
+        Command::Decrypt { files } => run_decrypt(&config, &files),
+        Command::Upload { files } => run_upload(&config, &files),
+        Command::Keygen {
+            user_id,
+            public_out,
+            secret_out,
+            protect,
+            force,
+        } => run_keygen(&config, &user_id, &public_out, &secret_out, protect, force),
+        Command::InstallService => {
+            config::setup_autostart();
+            Ok(())
+        }
+        Command::Verify {
+            file,
+            signature,
+            trusted,
+        } => run_verify(&config, &file, signature.as_deref(), &trusted),
+        Command::CheckConfig => run_check_config(&config),
+    }
+}
 
-    let watch_dir = load_watch_dir();
+fn needs(command: &Command) -> &'static [Need] {
+    match command {
+        Command::Watch | Command::CheckConfig => &[Need::WatchDir, Need::PublicKey, Need::Sftp],
+        Command::Encrypt { .. } => &[Need::PublicKey],
+        Command::Decrypt { .. } => &[Need::PrivateKey],
+        Command::Upload { .. } => &[Need::Sftp],
+        Command::Verify { .. } | Command::Keygen { .. } | Command::InstallService => &[],
+    }
+}
 
-    if !Path::new(&watch_dir).exists() {
-        eprintln!("WATCH_DIR '{}' does not exist.", watch_dir);
-        std::process::exit(1);
+fn run_watch(config: Config) -> Result<(), Box<dyn std::error::Error>> {
+    let recipients = load_default_recipients(&config.pgp)?;
+    let signer = load_signing_key(&config.pgp)?;
+    let mut roots = Vec::new();
+    for root in config.watch_roots() {
+        roots.push(Root::new(&config, &root, recipients.clone())?);
     }
 
     let shutdown_flag = Arc::new(AtomicBool::new(false));
@@ -34,14 +106,246 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         shutdown_handle.store(true, Ordering::Relaxed);
     })?;
 
-    println!("Watching directory: {}", watch_dir);
+    for root in &roots {
+        println!("Watching directory: {}", root.dir().display());
+    }
 
-    let watcher_cert = cert.clone();
     let watcher_handle = std::thread::spawn(move || {
-        let _ = start_watching(&watch_dir, shutdown_flag, watcher_cert);
+        let servers = Servers::new(&config);
+        start_watching(&config, &servers, signer.as_ref(), shutdown_flag, roots)
     });
 
-    watcher_handle.join().unwrap();
+    watcher_handle
+        .join()
+        .map_err(|_| "Watcher thread panicked")?
+        .map_err(|e| format!("Watching failed: {}", e))?;
+
+    Ok(())
+}
+
+fn run_encrypt(config: &Config, files: &[PathBuf]) -> Result<(), Box<dyn std::error::Error>> {
+    let recipients = load_default_recipients(&config.pgp)?;
+    let signer = load_signing_key(&config.pgp)?;
+
+    for file in files {
+        let name = format!(
+            "{}.pgp",
+            file.file_name()
+                .ok_or("Cannot encrypt a directory")?
+                .to_string_lossy()
+        );
+        let chosen = resolve_name(&name, config.collision, file, &mut |name| {
+            Ok(config.encrypted_dir.join(name).exists())
+        })?;
+        let Some(name) = chosen else {
+            println!("Skipping {}: {} already exists", file.display(), name);
+            continue;
+        };
+        let output = config.encrypted_dir.join(name);
+        encrypt_file_to(path_str(file)?, &recipients, signer.as_ref(), &output)?;
+        write_detached_signature(&config.pgp, signer.as_ref(), file, &output)?;
+        println!("Encrypted {} to {}", file.display(), output.display());
+    }
 
     Ok(())
 }
+
+fn run_decrypt(config: &Config, files: &[PathBuf]) -> Result<(), Box<dyn std::error::Error>> {
+    let key_path = config.pgp.private_key.as_deref().unwrap_or(Path::new(""));
+    let cert = load_secret_key(path_str(key_path)?)?;
+    let passphrase = config.pgp.private_key_passphrase.as_deref();
+
+    for file in files {
+        let output =
+            decrypt_file_with_pgp(path_str(file)?, &cert, passphrase, &config.decrypted_dir)?;
+        println!("Decrypted {} to {}", file.display(), output.display());
+    }
+
+    Ok(())
+}
+
+fn run_upload(config: &Config, files: &[PathBuf]) -> Result<(), Box<dyn std::error::Error>> {
+    let pool = SftpPool::new(config.sftp.clone());
+
+    for file in files {
+        let remote_name = Path::new(file.file_name().ok_or("Cannot upload a directory")?);
+        // A detached signature goes along, ahead of the file, as in `watch`.
+        let signature = signature_path(file);
+        if signature.exists() {
+            upload_file_with_retry(
+                path_str(&signature)?,
+                &signature_path(remote_name),
+                Destination::new(&pool),
+                &mut || {},
+            )?;
+        }
+        upload_file_with_retry(
+            path_str(file)?,
+            remote_name,
+            Destination::new(&pool),
+            &mut || {},
+        )?;
+    }
+
+    Ok(())
+}
+
+fn run_verify(
+    config: &Config,
+    file: &Path,
+    signature: Option<&Path>,
+    trusted: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let entries = if trusted.is_empty() {
+        &config.pgp.trusted_signers
+    } else {
+        trusted
+    };
+    if entries.is_empty() {
+        return Err("No trusted signers: set PGP_TRUSTED_SIGNERS or pass --trusted".into());
+    }
+    let certs = load_recipients(&config.pgp, entries)?;
+    let signature = signature
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| signature_path(file));
+
+    match verify_detached(file, &signature, &certs) {
+        Ok(signer) => {
+            let user_id = signer
+                .userids()
+                .next()
+                .map(|u| format!(" ({})", u.userid()))
+                .unwrap_or_default();
+            println!(
+                "Good signature on {} from {}{}",
+                file.display(),
+                signer.fingerprint(),
+                user_id
+            );
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("Bad signature on {}: {}", file.display(), e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run_keygen(
+    config: &Config,
+    user_id: &str,
+    public_out: &Path,
+    secret_out: &Path,
+    protect: bool,
+    force: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Overwriting the secret key would lose access to everything encrypted
+    // with it so far.
+    if !force {
+        for out in [public_out, secret_out] {
+            if out.exists() {
+                return Err(format!(
+                    "{} already exists; pass --force to overwrite it",
+                    out.display()
+                )
+                .into());
+            }
+        }
+    }
+
+    let password = if protect {
+        let passphrase = config
+            .pgp
+            .private_key_passphrase
+            .clone()
+            .ok_or("--protect requires PGP_PRIVATE_KEY_PASSPHRASE to be set")?;
+        Some(passphrase.into())
+    } else {
+        None
+    };
+
+    let (cert, _) = CertBuilder::general_purpose([user_id])
+        .set_password(password)
+        .generate()?;
+
+    for out in [public_out, secret_out] {
+        if let Some(parent) = out.parent() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+
+    let mut public_file = create_key_file(public_out, false, force)?;
+    public_file.write_all(&cert.armored().to_vec()?)?;
+    let mut secret_file = create_key_file(secret_out, true, force)?;
+    cert.as_tsk().armored().serialize(&mut secret_file)?;
+
+    println!("Generated key {}", cert.fingerprint());
+    println!("Public key: {}", public_out.display());
+    println!("Secret key: {}", secret_out.display());
+
+    Ok(())
+}
+
+/// Opens a key file for writing, refusing to replace an existing one unless
+/// `force` is set. Secret keys are readable by the owner only.
+fn create_key_file(path: &Path, secret: bool, force: bool) -> io::Result<fs::File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true);
+    if force {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    #[cfg(unix)]
+    if secret {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let file = options.open(path)?;
+    // The mode only applies to new files, so tighten a replaced one too.
+    #[cfg(unix)]
+    if secret {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(file)
+}
+
+/// Runs after `validate`, so only the checks that need to parse files are left.
+fn run_check_config(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let mut problems = Vec::new();
+
+    match load_default_recipients(&config.pgp) {
+        Ok(recipients) => {
+            for root in config.watch_roots() {
+                if let Err(e) = Root::new(config, &root, recipients.clone()) {
+                    problems.push(format!("{}: {}", root.dir.display(), e));
+                }
+            }
+        }
+        Err(e) => problems.push(format!("PGP recipients could not be loaded: {}", e)),
+    }
+
+    if let Err(e) = load_signing_key(&config.pgp) {
+        problems.push(format!("pgp.signing_key could not be loaded: {}", e));
+    }
+
+    if let Some(path) = &config.pgp.private_key {
+        if let Err(e) = load_secret_key(path_str(path)?) {
+            problems.push(format!("pgp.private_key could not be loaded: {}", e));
+        }
+    }
+
+    if problems.is_empty() {
+        println!("Configuration OK");
+        return Ok(());
+    }
+
+    eprint!("{}", config::ConfigError(problems));
+    std::process::exit(1);
+}
+
+fn path_str(path: &Path) -> Result<&str, Box<dyn std::error::Error>> {
+    path.to_str()
+        .ok_or_else(|| format!("Path is not valid UTF-8: {}", path.display()).into())
+}
@@ -1,58 +1,540 @@
 #![allow(unused)]
+use crate::agent::{agent_socket, get_passphrase};
+use crate::config::{DetachedSignature, PgpConfig};
 use anyhow::Result;
 use sequoia_openpgp::{
-    cert::Cert,
-    parse::Parse,
+    armor,
+    cert::{Cert, CertParser},
+    crypto::{KeyPair, Password, SessionKey},
+    packet::{Packet, PKESK, SKESK},
+    parse::{
+        stream::{
+            DecryptionHelper, DecryptorBuilder, DetachedVerifierBuilder, MessageLayer,
+            MessageStructure, VerificationHelper,
+        },
+        PacketParser, Parse,
+    },
     policy::StandardPolicy,
-    serialize::stream::{Encryptor, LiteralWriter, Message},
+    serialize::stream::{Armorer, Encryptor, LiteralWriter, Message, Signer},
+    types::SymmetricAlgorithm,
+    KeyHandle,
 };
 use std::fs::{self, File};
 use std::io::Write;
 use std::io::{self, Read};
 use std::path::{Path, PathBuf};
-use tempfile::tempdir;
-
-use crate::config;
+use std::process::Command;
+use tempfile::{tempdir, NamedTempFile};
+use zeroize::Zeroizing;
 
 pub fn load_public_key(path: &str) -> Result<Cert> {
     let mut file = File::open(path)?;
     let mut buf = Vec::new();
     file.read_to_end(&mut buf)?;
-    Ok(Cert::from_bytes(&buf)?)
+    Cert::from_bytes(&buf)
 }
 
-pub fn encrypt_file_with_pgp(
-    input_path: &str,
-    cert: &Cert,
-) -> Result<(), Box<dyn std::error::Error>> {
+/// Loads every certificate from a key file, a keyring of several
+/// certificates, or a directory of such files (not recursive).
+pub fn load_certs(path: &Path) -> Result<Vec<Cert>> {
+    if !path.is_dir() {
+        return CertParser::from_file(path)?
+            .collect::<Result<Vec<_>>>()
+            .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e));
+    }
+
+    let mut files = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+        if entry.file_type()?.is_file() && !hidden {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+
+    let mut certs = Vec::new();
+    for file in files {
+        certs.extend(load_certs(&file)?);
+    }
+    Ok(certs)
+}
+
+/// Loads the certificates for recipient entries, each either the name of a
+/// `[pgp.groups]` entry or a path accepted by [`load_certs`]. Duplicates are
+/// dropped.
+pub fn load_recipients(pgp: &PgpConfig, entries: &[String]) -> Result<Vec<Cert>> {
+    let mut certs: Vec<Cert> = Vec::new();
+    for entry in entries {
+        let paths = match pgp.groups.get(entry) {
+            Some(members) => members.clone(),
+            None => vec![PathBuf::from(entry)],
+        };
+        for path in paths {
+            for cert in load_certs(&path)? {
+                if !certs.iter().any(|c| c.fingerprint() == cert.fingerprint()) {
+                    certs.push(cert);
+                }
+            }
+        }
+    }
+    if certs.is_empty() {
+        return Err(anyhow::anyhow!("No recipient certificates found"));
+    }
+    Ok(certs)
+}
+
+/// The recipients every file is encrypted to by default: `pgp.public_key`
+/// plus `pgp.recipients`.
+pub fn load_default_recipients(pgp: &PgpConfig) -> Result<Vec<Cert>> {
+    let mut entries = Vec::new();
+    if !pgp.public_key.as_os_str().is_empty() {
+        entries.push(pgp.public_key.to_string_lossy().into_owned());
+    }
+    entries.extend(pgp.recipients.iter().cloned());
+    load_recipients(pgp, &entries)
+}
+
+pub fn load_secret_key(path: &str) -> Result<Cert> {
+    let cert = load_public_key(path)?;
+    if !cert.is_tsk() {
+        return Err(anyhow::anyhow!(
+            "{} does not contain secret key material",
+            path
+        ));
+    }
+    Ok(cert)
+}
+
+/// Loads the signing key from `pgp.signing_key`, unlocking it with the
+/// configured passphrase if it is protected. Returns `None` when signing is
+/// not configured.
+pub fn load_signing_key(pgp: &PgpConfig) -> Result<Option<KeyPair>> {
+    let Some(path) = &pgp.signing_key else {
+        return Ok(None);
+    };
+    let cert = load_secret_key(&path.to_string_lossy())?;
     let policy = &StandardPolicy::new();
 
-    let key = cert
+    let ka = cert
         .keys()
         .with_policy(policy, None)
+        .supported()
         .alive()
         .revoked(false)
-        .for_transport_encryption()
+        .for_signing()
+        .secret()
         .next()
-        .ok_or_else(|| anyhow::anyhow!("No suitable encryption key found"))?;
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "No usable signing key with secret key material in {}",
+                path.display()
+            )
+        })?;
+
+    let mut key = ka.key().clone();
+    if key.secret().is_encrypted() {
+        let password = signing_passphrase(pgp, &key.fingerprint().to_hex())?.ok_or_else(|| {
+            anyhow::anyhow!(
+                "Signing key {} is password protected but no passphrase was given",
+                path.display()
+            )
+        })?;
+        key = key.decrypt_secret(&password).map_err(|e| {
+            anyhow::anyhow!("Could not unlock signing key {}: {}", path.display(), e)
+        })?;
+    }
+    Ok(Some(key.into_keypair()?))
+}
+
+/// The signing key passphrase from the config or environment, the first
+/// line of a passphrase file, the output of a passphrase command, or
+/// gpg-agent's cache entry for `fingerprint`.
+fn signing_passphrase(pgp: &PgpConfig, fingerprint: &str) -> Result<Option<Password>> {
+    if let Some(passphrase) = &pgp.signing_key_passphrase {
+        return Ok(Some(Password::from(passphrase.as_str())));
+    }
+    if let Some(path) = &pgp.signing_key_passphrase_file {
+        let contents = Zeroizing::new(fs::read_to_string(path).map_err(|e| {
+            anyhow::anyhow!("Could not read passphrase file {}: {}", path.display(), e)
+        })?);
+        let line = contents.lines().next().unwrap_or_default();
+        return Ok(Some(Password::from(line)));
+    }
+    if let Some(command) = &pgp.signing_key_passphrase_command {
+        let output = if cfg!(target_os = "windows") {
+            Command::new("cmd").args(["/C", command]).output()?
+        } else {
+            Command::new("sh").args(["-c", command]).output()?
+        };
+        if !output.status.success() {
+            return Err(anyhow::anyhow!(
+                "Passphrase command exited with {}",
+                output.status
+            ));
+        }
+        let stdout = Zeroizing::new(output.stdout);
+        let stdout = std::str::from_utf8(&stdout)
+            .map_err(|_| anyhow::anyhow!("Passphrase command printed invalid UTF-8"))?;
+        let line = stdout.lines().next().unwrap_or_default();
+        return Ok(Some(Password::from(line)));
+    }
+    if pgp.signing_key_passphrase_agent {
+        let socket =
+            agent_socket().ok_or_else(|| anyhow::anyhow!("Cannot locate the gpg-agent socket"))?;
+        let description = format!(
+            "VaultSync needs the passphrase for signing key {}",
+            fingerprint
+        );
+        let passphrase = get_passphrase(&socket, fingerprint, &description)
+            .map_err(|e| anyhow::anyhow!("{}", e))?;
+        return Ok(Some(Password::from(passphrase.as_str())));
+    }
+    Ok(None)
+}
+
+/// Encrypts `input_path` into `output_dir` as `<name>.pgp` and returns the
+/// path of the encrypted file.
+pub fn encrypt_file_with_pgp(
+    input_path: &str,
+    recipients: &[Cert],
+    output_dir: &Path,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let filename = Path::new(input_path).file_name().unwrap().to_str().unwrap();
+    let output_path = output_dir.join(format!("{filename}.pgp"));
+    encrypt_file_to(input_path, recipients, None, &output_path)?;
+    Ok(output_path)
+}
+
+/// Encrypts `input_path` into `output_path`, replacing any existing file,
+/// so that any one of `recipients` can decrypt it. The original file name
+/// is stored in the literal data packet. With a `signer` the data is signed
+/// inside the encryption, so recipients can check who sent it.
+pub fn encrypt_file_to(
+    input_path: &str,
+    recipients: &[Cert],
+    signer: Option<&KeyPair>,
+    output_path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let policy = &StandardPolicy::new();
+
+    // Every valid encryption subkey of every recipient, so each of them can
+    // decrypt with whichever subkey they hold.
+    let mut keys = Vec::new();
+    for cert in recipients {
+        let before = keys.len();
+        keys.extend(
+            cert.keys()
+                .with_policy(policy, None)
+                .supported()
+                .alive()
+                .revoked(false)
+                .for_transport_encryption()
+                .for_storage_encryption(),
+        );
+        if keys.len() == before {
+            return Err(anyhow::anyhow!(
+                "No suitable encryption key found for {}",
+                cert.fingerprint()
+            )
+            .into());
+        }
+    }
 
     let input = Path::new(input_path);
     let filename = input.file_name().unwrap().to_str().unwrap();
-    let output_path: PathBuf = config::encrypted_output_dir().join(format!("{filename}.pgp"));
-
-    fs::create_dir_all(config::encrypted_output_dir())?;
 
     let mut input_file = File::open(input_path)?;
-    let mut output_file = File::create(&output_path)?;
+    // Written under a temporary name first, so a failed run never leaves a
+    // truncated `.pgp` that looks like a finished one.
+    let mut output_file = temp_file_beside(output_path)?;
 
     let message = Message::new(&mut output_file);
-    let encryptor = Encryptor::for_recipients(message, vec![key]).build()?;
-    let mut literal_writer = LiteralWriter::new(encryptor).build()?;
+    let mut message = Encryptor::for_recipients(message, keys).build()?;
+    if let Some(keypair) = signer {
+        let mut signer = Signer::new(message, keypair.clone())?;
+        for cert in recipients {
+            signer = signer.add_intended_recipient(cert);
+        }
+        message = signer.build()?;
+    }
+    let mut literal_writer = LiteralWriter::new(message).filename(filename)?.build()?;
     io::copy(&mut input_file, &mut literal_writer)?;
     literal_writer.finalize()?;
+    output_file.persist(output_path)?;
+
+    Ok(())
+}
+
+/// A temporary file in the directory of `path`, which is created if needed,
+/// so that it can be persisted as `path` with a rename.
+fn temp_file_beside(path: &Path) -> io::Result<NamedTempFile> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(dir)?;
+    NamedTempFile::new_in(dir)
+}
+
+/// Where the detached signature for `path` is written: `<path>.sig`.
+pub fn signature_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".sig");
+    PathBuf::from(name)
+}
+
+/// Writes a detached signature over `input` to `output`, ASCII-armored if
+/// `armored` is set.
+pub fn sign_detached(
+    input: &Path,
+    signer: &KeyPair,
+    armored: bool,
+    output: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut input_file = File::open(input)?;
+    let mut output_file = temp_file_beside(output)?;
+
+    let mut message = Message::new(&mut output_file);
+    if armored {
+        message = Armorer::new(message).kind(armor::Kind::Signature).build()?;
+    }
+    let mut signer = Signer::new(message, signer.clone())?.detached().build()?;
+    io::copy(&mut input_file, &mut signer)?;
+    signer.finalize()?;
+    output_file.persist(output)?;
 
     Ok(())
 }
+
+/// Writes the `.sig` for `encrypted` if `pgp.detached_signature` is enabled,
+/// signing either the original `source` or the encrypted file.
+pub fn write_detached_signature(
+    pgp: &PgpConfig,
+    signer: Option<&KeyPair>,
+    source: &Path,
+    encrypted: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let signed = match pgp.detached_signature {
+        DetachedSignature::Off => return Ok(()),
+        DetachedSignature::Plaintext => source,
+        DetachedSignature::Ciphertext => encrypted,
+    };
+    let signer = signer.ok_or("Detached signatures need pgp.signing_key")?;
+    sign_detached(
+        signed,
+        signer,
+        pgp.detached_signature_armor,
+        &signature_path(encrypted),
+    )
+}
+
+/// Checks the detached `signature` over `data` against `trusted` and returns
+/// the certificate that made the first good signature.
+pub fn verify_detached(
+    data: &Path,
+    signature: &Path,
+    trusted: &[Cert],
+) -> Result<Cert, Box<dyn std::error::Error>> {
+    let policy = &StandardPolicy::new();
+    let helper = VerifyHelper {
+        trusted,
+        signer: None,
+    };
+
+    let mut verifier =
+        DetachedVerifierBuilder::from_file(signature)?.with_policy(policy, None, helper)?;
+    verifier.verify_file(data)?;
+
+    let signer = verifier.into_helper().signer;
+    signer.ok_or_else(|| "No good signature".into())
+}
+
+struct VerifyHelper<'a> {
+    trusted: &'a [Cert],
+    signer: Option<Cert>,
+}
+
+impl VerificationHelper for VerifyHelper<'_> {
+    fn get_certs(&mut self, _ids: &[KeyHandle]) -> Result<Vec<Cert>> {
+        Ok(self.trusted.to_vec())
+    }
+
+    fn check(&mut self, structure: MessageStructure) -> Result<()> {
+        let mut errors = Vec::new();
+        for layer in structure {
+            if let MessageLayer::SignatureGroup { results } = layer {
+                for result in results {
+                    match result {
+                        Ok(good) => {
+                            self.signer = Some(good.ka.cert().clone());
+                            return Ok(());
+                        }
+                        Err(e) => errors.push(e.to_string()),
+                    }
+                }
+            }
+        }
+        if errors.is_empty() {
+            Err(anyhow::anyhow!("No signature found"))
+        } else {
+            Err(anyhow::anyhow!("No good signature: {}", errors.join("; ")))
+        }
+    }
+}
+
+/// Decrypts a `.pgp` file into `output_dir`, restoring the file name
+/// stored in the literal data packet. Returns the path of the plaintext.
+pub fn decrypt_file_with_pgp(
+    input_path: &str,
+    cert: &Cert,
+    password: Option<&str>,
+    output_dir: &Path,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let policy = &StandardPolicy::new();
+
+    let helper = DecryptHelper {
+        cert,
+        password: password.map(Password::from),
+        filename: None,
+    };
+
+    let input_file = File::open(input_path)?;
+    let mut decryptor =
+        DecryptorBuilder::from_reader(input_file)?.with_policy(policy, None, helper)?;
+
+    fs::create_dir_all(output_dir)?;
+
+    let filename = decryptor
+        .helper_ref()
+        .filename
+        .as_deref()
+        .and_then(literal_file_name)
+        .unwrap_or_else(|| fallback_file_name(Path::new(input_path)));
+    let output_path = output_dir.join(filename);
+
+    // Plaintext only reaches its final name once the whole message has
+    // been authenticated, and never replaces an existing file.
+    let mut output_file = NamedTempFile::new_in(output_dir)?;
+    io::copy(&mut decryptor, &mut output_file)?;
+    output_file
+        .persist_noclobber(&output_path)
+        .map_err(|e| match e.error.kind() {
+            io::ErrorKind::AlreadyExists => {
+                format!(
+                    "{} already exists, not overwriting it",
+                    output_path.display()
+                )
+            }
+            _ => format!("Failed to write {}: {}", output_path.display(), e.error),
+        })?;
+
+    Ok(output_path)
+}
+
+/// Only the final component of the stored name is used, so a crafted
+/// message cannot write outside of the output directory.
+fn literal_file_name(raw: &[u8]) -> Option<String> {
+    let name = String::from_utf8_lossy(raw);
+    Path::new(name.as_ref())
+        .file_name()
+        .and_then(|n| n.to_str())
+        .filter(|n| !n.is_empty())
+        .map(str::to_string)
+}
+
+fn fallback_file_name(input: &Path) -> String {
+    match input.extension().and_then(|e| e.to_str()) {
+        Some("pgp") => input.file_stem(),
+        _ => input.file_name(),
+    }
+    .map(|n| n.to_string_lossy().into_owned())
+    .unwrap_or_else(|| "decrypted".to_string())
+}
+
+struct DecryptHelper<'a> {
+    cert: &'a Cert,
+    password: Option<Password>,
+    filename: Option<Vec<u8>>,
+}
+
+impl VerificationHelper for DecryptHelper<'_> {
+    fn get_certs(&mut self, _ids: &[KeyHandle]) -> Result<Vec<Cert>> {
+        Ok(Vec::new())
+    }
+
+    fn check(&mut self, _structure: MessageStructure) -> Result<()> {
+        Ok(())
+    }
+
+    fn inspect(&mut self, pp: &PacketParser) -> Result<()> {
+        if let Packet::Literal(literal) = &pp.packet {
+            self.filename = literal.filename().map(|f| f.to_vec());
+        }
+        Ok(())
+    }
+}
+
+impl DecryptionHelper for DecryptHelper<'_> {
+    fn decrypt(
+        &mut self,
+        pkesks: &[PKESK],
+        _skesks: &[SKESK],
+        sym_algo: Option<SymmetricAlgorithm>,
+        decrypt: &mut dyn FnMut(Option<SymmetricAlgorithm>, &SessionKey) -> bool,
+    ) -> Result<Option<Cert>> {
+        let policy = &StandardPolicy::new();
+
+        for ka in self
+            .cert
+            .keys()
+            .with_policy(policy, None)
+            .for_transport_encryption()
+            .for_storage_encryption()
+            .secret()
+        {
+            let mut key = ka.key().clone();
+            if key.secret().is_encrypted() {
+                let password = self.password.as_ref().ok_or_else(|| {
+                    anyhow::anyhow!("Secret key is password protected but no passphrase was given")
+                })?;
+                key = key.decrypt_secret(password)?;
+            }
+            let mut keypair = key.into_keypair()?;
+
+            for pkesk in pkesks {
+                let addressed = pkesk
+                    .recipient()
+                    .map(|r| r.aliases(ka.key().key_handle()))
+                    .unwrap_or(true);
+                if addressed
+                    && pkesk
+                        .decrypt(&mut keypair, sym_algo)
+                        .map(|(algo, sk)| decrypt(algo, &sk))
+                        .unwrap_or(false)
+                {
+                    return Ok(Some(self.cert.clone()));
+                }
+            }
+        }
+
+        Err(anyhow::anyhow!(
+            "No key in the certificate could decrypt the message"
+        ))
+    }
+}
+
+#[cfg(test)]
+fn generate_test_cert(password: Option<&str>) -> Cert {
+    use sequoia_openpgp::cert::CertBuilder;
+
+    let (cert, _) = CertBuilder::general_purpose(["VaultSync Test <test@vaultsync.local>"])
+        .set_password(password.map(Password::from))
+        .generate()
+        .unwrap();
+    cert
+}
+
 #[test]
 fn test_encrypt_file_with_pgp_creates_output() {
     let dir = tempdir().unwrap();
@@ -61,15 +543,273 @@ fn test_encrypt_file_with_pgp_creates_output() {
     let mut input = File::create(&input_path).unwrap();
     writeln!(input, "Test PGP data").unwrap();
 
-    let cert = load_public_key("keys/recipient.asc").unwrap();
+    let cert = generate_test_cert(None);
+    let output_dir = dir.path().join("encrypted");
 
-    encrypt_file_with_pgp(input_path.to_str().unwrap(), &cert).expect("Encryption failed");
+    encrypt_file_with_pgp(input_path.to_str().unwrap(), &[cert], &output_dir)
+        .expect("Encryption failed");
 
     let filename = input_path.file_name().unwrap().to_str().unwrap();
-    let output_path = config::encrypted_output_dir().join(format!("{filename}.pgp"));
+    let output_path = output_dir.join(format!("{filename}.pgp"));
 
     assert!(
         output_path.exists(),
         "PGP file was not created at expected path"
     );
 }
+
+#[test]
+fn test_decrypt_file_with_pgp_restores_name_and_content() {
+    let dir = tempdir().unwrap();
+    let input_path = dir.path().join("roundtrip.txt");
+    fs::write(&input_path, b"PGP roundtrip data").unwrap();
+
+    let cert = generate_test_cert(Some("hunter2"));
+    let encrypted_path = encrypt_file_with_pgp(
+        input_path.to_str().unwrap(),
+        std::slice::from_ref(&cert),
+        &dir.path().join("encrypted"),
+    )
+    .expect("Encryption failed");
+    let encrypted = encrypted_path.to_str().unwrap();
+    let decrypted_dir = dir.path().join("decrypted");
+
+    let decrypted_path = decrypt_file_with_pgp(encrypted, &cert, Some("hunter2"), &decrypted_dir)
+        .expect("Decryption failed");
+
+    assert_eq!(decrypted_path.file_name().unwrap(), "roundtrip.txt");
+    assert_eq!(fs::read(&decrypted_path).unwrap(), b"PGP roundtrip data");
+
+    assert!(
+        decrypt_file_with_pgp(encrypted, &cert, None, &decrypted_dir).is_err(),
+        "Decryption without a passphrase should fail for a protected key"
+    );
+
+    assert!(
+        decrypt_file_with_pgp(encrypted, &cert, Some("hunter2"), &decrypted_dir).is_err(),
+        "An existing plaintext must not be overwritten"
+    );
+    assert_eq!(fs::read(&decrypted_path).unwrap(), b"PGP roundtrip data");
+
+    // A corrupted message leaves nothing behind.
+    let mut tampered = fs::read(&encrypted_path).unwrap();
+    let middle = tampered.len() / 2;
+    tampered[middle] ^= 0xff;
+    let tampered_path = dir.path().join("tampered.pgp");
+    fs::write(&tampered_path, tampered).unwrap();
+    let tampered_dir = dir.path().join("tampered");
+    assert!(decrypt_file_with_pgp(
+        tampered_path.to_str().unwrap(),
+        &cert,
+        Some("hunter2"),
+        &tampered_dir
+    )
+    .is_err());
+    assert_eq!(fs::read_dir(&tampered_dir).map_or(0, |d| d.count()), 0);
+}
+
+#[test]
+fn test_every_recipient_can_decrypt() {
+    use sequoia_openpgp::serialize::{Serialize, SerializeInto};
+
+    let dir = tempdir().unwrap();
+    let input_path = dir.path().join("shared.txt");
+    fs::write(&input_path, b"for team, customer and escrow").unwrap();
+
+    let team = generate_test_cert(None);
+    let customer = generate_test_cert(None);
+    let escrow = generate_test_cert(None);
+
+    // The team key on its own, the other two in a keyring directory.
+    let keys = dir.path().join("keys");
+    fs::create_dir_all(keys.join("ring")).unwrap();
+    fs::write(keys.join("team.asc"), team.armored().to_vec().unwrap()).unwrap();
+    let mut ring = Vec::new();
+    customer.serialize(&mut ring).unwrap();
+    escrow.serialize(&mut ring).unwrap();
+    fs::write(keys.join("ring/partners.pgp"), ring).unwrap();
+
+    let pgp = PgpConfig {
+        public_key: keys.join("team.asc"),
+        recipients: vec!["partners".to_string()],
+        groups: [("partners".to_string(), vec![keys.join("ring")])].into(),
+        ..PgpConfig::default()
+    };
+    let recipients = load_default_recipients(&pgp).unwrap();
+    assert_eq!(recipients.len(), 3);
+
+    let encrypted = encrypt_file_with_pgp(
+        input_path.to_str().unwrap(),
+        &recipients,
+        &dir.path().join("encrypted"),
+    )
+    .unwrap();
+
+    for cert in [&team, &customer, &escrow] {
+        let output = dir.path().join(cert.fingerprint().to_hex());
+        let decrypted =
+            decrypt_file_with_pgp(encrypted.to_str().unwrap(), cert, None, &output).unwrap();
+        assert_eq!(
+            fs::read(decrypted).unwrap(),
+            b"for team, customer and escrow"
+        );
+    }
+}
+
+#[cfg(test)]
+struct SignedBy<'a> {
+    decrypt: DecryptHelper<'a>,
+    signer: &'a Cert,
+}
+
+#[cfg(test)]
+impl VerificationHelper for SignedBy<'_> {
+    fn get_certs(&mut self, _ids: &[KeyHandle]) -> Result<Vec<Cert>> {
+        Ok(vec![self.signer.clone()])
+    }
+
+    fn check(&mut self, structure: MessageStructure) -> Result<()> {
+        for layer in structure {
+            if let MessageLayer::SignatureGroup { results } = layer {
+                if results.iter().any(|r| r.is_ok()) {
+                    return Ok(());
+                }
+            }
+        }
+        Err(anyhow::anyhow!("No good signature"))
+    }
+}
+
+#[cfg(test)]
+impl DecryptionHelper for SignedBy<'_> {
+    fn decrypt(
+        &mut self,
+        pkesks: &[PKESK],
+        skesks: &[SKESK],
+        sym_algo: Option<SymmetricAlgorithm>,
+        decrypt: &mut dyn FnMut(Option<SymmetricAlgorithm>, &SessionKey) -> bool,
+    ) -> Result<Option<Cert>> {
+        self.decrypt.decrypt(pkesks, skesks, sym_algo, decrypt)
+    }
+}
+
+#[test]
+fn test_signed_files_verify_against_the_signer() {
+    use sequoia_openpgp::serialize::SerializeInto;
+
+    let dir = tempdir().unwrap();
+    let input_path = dir.path().join("signed.txt");
+    fs::write(&input_path, b"from us").unwrap();
+
+    let recipient = generate_test_cert(None);
+    let ours = generate_test_cert(Some("sign me"));
+    let key_path = dir.path().join("signing.asc");
+    fs::write(&key_path, ours.as_tsk().armored().to_vec().unwrap()).unwrap();
+
+    let mut pgp = PgpConfig {
+        signing_key: Some(key_path),
+        ..PgpConfig::default()
+    };
+    assert!(
+        load_signing_key(&pgp).is_err(),
+        "A protected signing key needs a passphrase"
+    );
+    pgp.signing_key_passphrase_command = Some("echo 'sign me'".to_string());
+    let keypair = load_signing_key(&pgp).unwrap().unwrap();
+
+    let encrypted = dir.path().join("signed.txt.pgp");
+    encrypt_file_to(
+        input_path.to_str().unwrap(),
+        std::slice::from_ref(&recipient),
+        Some(&keypair),
+        &encrypted,
+    )
+    .unwrap();
+
+    let policy = &StandardPolicy::new();
+    let decrypt_with = |signer: &Cert| {
+        let helper = SignedBy {
+            decrypt: DecryptHelper {
+                cert: &recipient,
+                password: None,
+                filename: None,
+            },
+            signer,
+        };
+        let mut plaintext = Vec::new();
+        DecryptorBuilder::from_file(&encrypted)?
+            .with_policy(policy, None, helper)?
+            .read_to_end(&mut plaintext)?;
+        Ok::<_, anyhow::Error>(plaintext)
+    };
+
+    assert_eq!(decrypt_with(&ours).unwrap(), b"from us");
+    assert!(
+        decrypt_with(&generate_test_cert(None)).is_err(),
+        "A different certificate must not verify the signature"
+    );
+}
+
+#[test]
+fn test_detached_signatures_verify_against_trusted_certs() {
+    let dir = tempdir().unwrap();
+    let data = dir.path().join("report.csv");
+    fs::write(&data, b"a,b,c").unwrap();
+
+    let ours = generate_test_cert(None);
+    let stranger = generate_test_cert(None);
+    let keypair = ours
+        .keys()
+        .with_policy(&StandardPolicy::new(), None)
+        .for_signing()
+        .secret()
+        .next()
+        .unwrap()
+        .key()
+        .clone()
+        .into_keypair()
+        .unwrap();
+
+    for armored in [false, true] {
+        let signature = signature_path(&data);
+        sign_detached(&data, &keypair, armored, &signature).unwrap();
+        assert_eq!(
+            fs::read(&signature)
+                .unwrap()
+                .starts_with(b"-----BEGIN PGP SIGNATURE-----"),
+            armored
+        );
+
+        let signer = verify_detached(&data, &signature, &[stranger.clone(), ours.clone()]).unwrap();
+        assert_eq!(signer.fingerprint(), ours.fingerprint());
+        assert!(
+            verify_detached(&data, &signature, std::slice::from_ref(&stranger)).is_err(),
+            "An untrusted signer must not verify"
+        );
+    }
+
+    fs::write(&data, b"a,b,tampered").unwrap();
+    let signature = signature_path(&data);
+    assert!(verify_detached(&data, &signature, std::slice::from_ref(&ours)).is_err());
+}
+
+#[test]
+fn test_failed_encryption_leaves_no_output() {
+    let dir = tempdir().unwrap();
+    let cert = generate_test_cert(None);
+    let output_dir = dir.path().join("encrypted");
+    let output = output_dir.join("broken.pgp");
+
+    // Opening a directory succeeds on Unix, reading from it fails mid-stream.
+    let unreadable = dir.path().join("not-a-file");
+    fs::create_dir(&unreadable).unwrap();
+    assert!(encrypt_file_to(
+        unreadable.to_str().unwrap(),
+        std::slice::from_ref(&cert),
+        None,
+        &output
+    )
+    .is_err());
+
+    assert_eq!(fs::read_dir(&output_dir).map_or(0, |d| d.count()), 0);
+}
@@ -0,0 +1,277 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// How far a job has progressed through the pipeline. Each stage is
+/// recorded once it has completed, so a restarted job picks up at the next
+/// step instead of starting over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Stage {
+    Detected,
+    Encrypted,
+    Uploaded,
+    Verified,
+    CleanedUp,
+}
+
+/// One file travelling through encrypt, upload, verify and clean-up.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Job {
+    pub source: PathBuf,
+    pub stage: Stage,
+    pub encrypted: Option<PathBuf>,
+    /// Size and modification time of the source when it was encrypted.
+    #[serde(default)]
+    pub source_fingerprint: Option<String>,
+    /// Number of times the job has failed so far.
+    pub failures: u32,
+    pub last_error: Option<String>,
+    /// Unix time before which a failed job is not picked up again.
+    pub retry_at: u64,
+}
+
+impl Job {
+    pub fn new(source: &Path) -> Self {
+        Job {
+            source: source.to_path_buf(),
+            stage: Stage::Detected,
+            encrypted: None,
+            source_fingerprint: None,
+            failures: 0,
+            last_error: None,
+            retry_at: 0,
+        }
+    }
+
+    /// Records a failure and postpones the job by `delay`.
+    pub fn fail(&mut self, error: &str, delay: Duration) {
+        self.failures += 1;
+        self.last_error = Some(error.to_string());
+        self.retry_at = unix_now() + delay.as_secs();
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.retry_at <= unix_now()
+    }
+
+    /// Remembers the source's current state before it is encrypted.
+    pub fn record_source(&mut self) {
+        self.source_fingerprint = fingerprint(&self.source);
+    }
+
+    /// Whether the source was modified or removed since it was encrypted.
+    pub fn source_changed(&self) -> bool {
+        self.source_fingerprint.is_some() && fingerprint(&self.source) != self.source_fingerprint
+    }
+}
+
+/// Persistent job journal: one small TOML file per job under
+/// `<state_dir>/jobs`, rewritten atomically whenever the job changes and
+/// removed once the job is cleaned up. Originals that are kept after upload
+/// are remembered under `<state_dir>/done`.
+///
+/// The journal is only read when the queue is opened; after that a copy of
+/// every unfinished job is kept in memory and updated alongside the files.
+pub struct JobQueue {
+    dir: PathBuf,
+    done_dir: PathBuf,
+    pending: Mutex<HashMap<PathBuf, Job>>,
+}
+
+impl JobQueue {
+    pub fn open(state_dir: &Path) -> io::Result<Self> {
+        let dir = state_dir.join("jobs");
+        let done_dir = state_dir.join("done");
+        fs::create_dir_all(&dir)?;
+        fs::create_dir_all(&done_dir)?;
+        let pending = load_jobs(&dir)?
+            .into_iter()
+            .map(|job| (job.source.clone(), job))
+            .collect();
+        Ok(JobQueue {
+            dir,
+            done_dir,
+            pending: Mutex::new(pending),
+        })
+    }
+
+    /// Records a new job for `source`. Returns `None` if the file already
+    /// has an unfinished job, or was processed and has not changed since.
+    pub fn enqueue(&self, source: &Path) -> io::Result<Option<Job>> {
+        if self.pending.lock().unwrap().contains_key(source) || self.is_done(source) {
+            return Ok(None);
+        }
+        let job = Job::new(source);
+        self.save(&job)?;
+        Ok(Some(job))
+    }
+
+    pub fn save(&self, job: &Job) -> io::Result<()> {
+        let contents =
+            toml::to_string(job).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let path = self.job_path(&job.source);
+        let temp = path.with_extension("toml.tmp");
+
+        let mut file = File::create(&temp)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp, &path)?;
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(job.source.clone(), job.clone());
+        Ok(())
+    }
+
+    pub fn remove(&self, job: &Job) -> io::Result<()> {
+        match fs::remove_file(self.job_path(&job.source)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        self.pending.lock().unwrap().remove(&job.source);
+        Ok(())
+    }
+
+    /// Every unfinished job, oldest failure first.
+    pub fn jobs(&self) -> Vec<Job> {
+        let mut jobs: Vec<Job> = self.pending.lock().unwrap().values().cloned().collect();
+        jobs.sort_by_key(|job| job.retry_at);
+        jobs
+    }
+
+    /// Remembers `source` in its current state, so that an original left
+    /// in place is not queued again until it changes.
+    pub fn mark_done(&self, source: &Path) -> io::Result<()> {
+        match fingerprint(source) {
+            Some(fingerprint) => fs::write(self.done_dir.join(job_id(source)), fingerprint),
+            None => Ok(()),
+        }
+    }
+
+    fn is_done(&self, source: &Path) -> bool {
+        match fs::read_to_string(self.done_dir.join(job_id(source))) {
+            Ok(done) => fingerprint(source).is_some_and(|current| current == done),
+            Err(_) => false,
+        }
+    }
+
+    fn job_path(&self, source: &Path) -> PathBuf {
+        self.dir.join(format!("{}.toml", job_id(source)))
+    }
+}
+
+/// Reads the journal in `dir`. Unreadable entries are reported and skipped.
+fn load_jobs(dir: &Path) -> io::Result<Vec<Job>> {
+    let mut jobs = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("toml") {
+            continue;
+        }
+        let parsed = fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|s| toml::from_str::<Job>(&s).map_err(|e| e.to_string()));
+        match parsed {
+            Ok(job) => jobs.push(job),
+            Err(e) => eprintln!("Skipping unreadable job {}: {}", path.display(), e),
+        }
+    }
+    Ok(jobs)
+}
+
+fn job_id(source: &Path) -> String {
+    let digest = Sha256::digest(source.to_string_lossy().as_bytes());
+    digest[..16].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Size and modification time of `path`.
+fn fingerprint(path: &Path) -> Option<String> {
+    let metadata = fs::metadata(path).ok()?;
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(format!("{} {}", metadata.len(), modified.as_nanos()))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jobs_survive_reopening() {
+        let state = tempfile::tempdir().unwrap();
+        let source = Path::new("/watch/report.csv");
+
+        let queue = JobQueue::open(state.path()).unwrap();
+        let mut job = queue.enqueue(source).unwrap().unwrap();
+        assert!(queue.enqueue(source).unwrap().is_none());
+
+        job.stage = Stage::Encrypted;
+        job.encrypted = Some(PathBuf::from("/encrypted/report.csv.pgp"));
+        queue.save(&job).unwrap();
+
+        assert_eq!(queue.jobs(), vec![job.clone()]);
+        let reopened = JobQueue::open(state.path()).unwrap();
+        assert_eq!(reopened.jobs(), vec![job.clone()]);
+
+        reopened.remove(&job).unwrap();
+        assert!(reopened.jobs().is_empty());
+        assert!(JobQueue::open(state.path()).unwrap().jobs().is_empty());
+    }
+
+    #[test]
+    fn test_done_file_is_not_queued_until_it_changes() {
+        let state = tempfile::tempdir().unwrap();
+        let source = state.path().join("kept.txt");
+        fs::write(&source, "v1").unwrap();
+
+        let queue = JobQueue::open(state.path()).unwrap();
+        let job = queue.enqueue(&source).unwrap().unwrap();
+        queue.remove(&job).unwrap();
+        queue.mark_done(&source).unwrap();
+        assert!(queue.enqueue(&source).unwrap().is_none());
+
+        fs::write(&source, "version 2").unwrap();
+        assert!(queue.enqueue(&source).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_source_changed_after_encryption() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("report.csv");
+        fs::write(&source, "v1").unwrap();
+
+        let mut job = Job::new(&source);
+        assert!(!job.source_changed(), "Nothing recorded yet");
+        job.record_source();
+        assert!(!job.source_changed());
+
+        fs::write(&source, "version 2").unwrap();
+        assert!(job.source_changed());
+    }
+
+    #[test]
+    fn test_failed_job_is_postponed() {
+        let mut job = Job::new(Path::new("a.txt"));
+        assert!(job.is_due());
+
+        job.fail("connection reset", Duration::from_secs(60));
+
+        assert!(!job.is_due());
+        assert_eq!(job.failures, 1);
+        assert_eq!(job.last_error.as_deref(), Some("connection reset"));
+    }
+}
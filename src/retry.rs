@@ -0,0 +1,188 @@
+use std::{
+    collections::hash_map::RandomState,
+    error::Error,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    io,
+    time::Duration,
+};
+
+/// libssh2 session errors caused by credentials or setup rather than the network.
+const PERMANENT_SESSION_CODES: [i32; 10] = [
+    -15, // PASSWORD_EXPIRED
+    -16, // FILE (unreadable or invalid key file)
+    -17, // METHOD_NONE
+    -18, // AUTHENTICATION_FAILED
+    -19, // PUBLICKEY_UNVERIFIED
+    -32, // REQUEST_DENIED
+    -33, // METHOD_NOT_SUPPORTED
+    -46, // KNOWN_HOSTS
+    -48, // KEYFILE_AUTH_FAILED
+    -51, // ALGO_UNSUPPORTED
+];
+
+/// SFTP status codes that the same request will hit again on every attempt.
+const PERMANENT_SFTP_CODES: [i32; 9] = [
+    2,  // NO_SUCH_FILE
+    3,  // PERMISSION_DENIED
+    8,  // OP_UNSUPPORTED
+    10, // NO_SUCH_PATH
+    12, // WRITE_PROTECT
+    14, // NO_SPACE_ON_FILESYSTEM
+    15, // QUOTA_EXCEEDED
+    19, // NOT_A_DIRECTORY
+    20, // INVALID_FILENAME
+];
+
+/// libssh2 session errors raised by a dropped or stalled connection.
+const TRANSIENT_SESSION_CODES: [i32; 7] = [
+    -2,  // BANNER_RECV
+    -7,  // SOCKET_SEND
+    -9,  // TIMEOUT
+    -13, // SOCKET_DISCONNECT
+    -30, // SOCKET_TIMEOUT
+    -37, // EAGAIN
+    -43, // SOCKET_RECV
+];
+
+/// An error that retrying cannot fix, such as a rejected login or a changed
+/// host key.
+#[derive(Debug)]
+pub struct PermanentError(pub Box<dyn Error>);
+
+impl fmt::Display for PermanentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Error for PermanentError {}
+
+/// Marks `err` as permanent unless it was caused by the network, so a
+/// timeout during authentication is still retried.
+pub fn permanent_unless_transient(err: Box<dyn Error>) -> Box<dyn Error> {
+    if is_transient(err.as_ref()) {
+        err
+    } else {
+        Box::new(PermanentError(err))
+    }
+}
+
+/// Whether another attempt might succeed. Errors that are not recognised as
+/// permanent are retried.
+pub fn is_retryable(err: &(dyn Error + 'static)) -> bool {
+    if err.is::<PermanentError>() {
+        return false;
+    }
+    if let Some(e) = err.downcast_ref::<ssh2::Error>() {
+        return match e.code() {
+            ssh2::ErrorCode::Session(code) => !PERMANENT_SESSION_CODES.contains(&code),
+            ssh2::ErrorCode::SFTP(code) => !PERMANENT_SFTP_CODES.contains(&code),
+        };
+    }
+    if let Some(e) = err.downcast_ref::<io::Error>() {
+        return !matches!(
+            e.kind(),
+            io::ErrorKind::PermissionDenied
+                | io::ErrorKind::NotFound
+                | io::ErrorKind::InvalidInput
+                | io::ErrorKind::Unsupported
+        );
+    }
+    true
+}
+
+/// Whether `err` was positively caused by the network: a refused, reset or
+/// timed-out connection.
+fn is_transient(err: &(dyn Error + 'static)) -> bool {
+    if let Some(e) = err.downcast_ref::<ssh2::Error>() {
+        return match e.code() {
+            ssh2::ErrorCode::Session(code) => TRANSIENT_SESSION_CODES.contains(&code),
+            ssh2::ErrorCode::SFTP(_) => false,
+        };
+    }
+    if let Some(e) = err.downcast_ref::<io::Error>() {
+        return matches!(
+            e.kind(),
+            io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::NotConnected
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::UnexpectedEof
+                | io::ErrorKind::WouldBlock
+                | io::ErrorKind::Interrupted
+        );
+    }
+    false
+}
+
+/// Delay before retry number `attempt` (1 for the first retry): `base`
+/// doubled per attempt, capped at `max`, then scaled by a random factor
+/// between 0.5 and 1 so that concurrent uploads do not retry in lockstep.
+pub fn backoff_delay(attempt: u32, base: Duration, max: Duration) -> Duration {
+    let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+    let delay = base.saturating_mul(factor).min(max);
+    delay.mul_f64(0.5 + random_unit() / 2.0)
+}
+
+/// A random number in `[0, 1)`, seeded by the standard library's per-process
+/// hash keys.
+fn random_unit() -> f64 {
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_and_caps() {
+        let base = Duration::from_millis(100);
+        let max = Duration::from_secs(1);
+
+        for _ in 0..20 {
+            let first = backoff_delay(1, base, max);
+            assert!(first >= Duration::from_millis(50) && first <= base);
+
+            let third = backoff_delay(3, base, max);
+            assert!(third >= Duration::from_millis(200) && third <= Duration::from_millis(400));
+
+            let capped = backoff_delay(30, base, max);
+            assert!(capped >= max / 2 && capped <= max);
+        }
+    }
+
+    #[test]
+    fn test_classifies_errors() {
+        let auth: Box<dyn Error> = Box::new(ssh2::Error::new(
+            ssh2::ErrorCode::Session(-18),
+            "Authentication failed",
+        ));
+        let timeout: Box<dyn Error> =
+            Box::new(ssh2::Error::new(ssh2::ErrorCode::Session(-9), "Timed out"));
+        let denied: Box<dyn Error> = Box::new(ssh2::Error::new(
+            ssh2::ErrorCode::SFTP(3),
+            "Permission denied",
+        ));
+        let reset: Box<dyn Error> = Box::new(io::Error::from(io::ErrorKind::ConnectionReset));
+        let other: Box<dyn Error> = "size mismatch".into();
+
+        assert!(!is_retryable(auth.as_ref()));
+        assert!(is_retryable(timeout.as_ref()));
+        assert!(!is_retryable(denied.as_ref()));
+        assert!(is_retryable(reset.as_ref()));
+        assert!(is_retryable(other.as_ref()));
+    }
+
+    #[test]
+    fn test_permanent_unless_transient() {
+        let timeout: Box<dyn Error> = Box::new(io::Error::from(io::ErrorKind::TimedOut));
+        let rejected: Box<dyn Error> = "No ssh-agent identity was accepted".into();
+
+        assert!(is_retryable(permanent_unless_transient(timeout).as_ref()));
+        assert!(!is_retryable(permanent_unless_transient(rejected).as_ref()));
+    }
+}
@@ -0,0 +1,215 @@
+use crate::{
+    config::{CleanupConfig, Config, WatchRootConfig},
+    filter::Rule,
+    pgp::load_recipients,
+    sftp::{Destination, SftpPool},
+};
+use sequoia_openpgp::Cert;
+use std::{collections::BTreeMap, error::Error, path::Path};
+
+/// Recipients, destination and cleanup policy for the files one route
+/// matches.
+pub struct Route {
+    /// `None` for the fallback built from the top-level settings.
+    rule: Option<Rule>,
+    pub recipients: Vec<Cert>,
+    pub server: Option<String>,
+    pub remote_dir: Option<String>,
+    pub cleanup: CleanupConfig,
+}
+
+impl Route {
+    /// Whether the route applies to `relative`, a path below `watch_dir`:
+    /// the pattern matches the file itself or one of its directories.
+    fn matches(&self, relative: &Path) -> bool {
+        let Some(rule) = &self.rule else {
+            return true;
+        };
+        rule.matches(relative, false)
+            || relative
+                .ancestors()
+                .skip(1)
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .any(|dir| rule.matches(dir, true))
+    }
+}
+
+/// Picks the route for each file in one watched folder from the
+/// `[[route]]` table, falling back to the folder's own settings when no
+/// route matches.
+pub struct Router {
+    routes: Vec<Route>,
+    fallback: Route,
+}
+
+impl Router {
+    /// Loads each route's recipients up front, so a missing key fails at
+    /// startup rather than on the first matching file.
+    pub fn new(
+        config: &Config,
+        root: &WatchRootConfig,
+        default_recipients: Vec<Cert>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let default_recipients = match &root.recipients {
+            Some(entries) => load_recipients(&config.pgp, entries)
+                .map_err(|e| format!("{}: {}", root.dir.display(), e))?,
+            None => default_recipients,
+        };
+
+        let mut routes = Vec::new();
+        for (i, route) in config.routes.iter().enumerate() {
+            let rule = Rule::parse(&route.pattern)
+                .map_err(|e| format!("route[{}]: {}", i + 1, e))?
+                .ok_or_else(|| format!("route[{}]: empty pattern", i + 1))?;
+            let recipients = match &route.recipients {
+                Some(entries) => load_recipients(&config.pgp, entries)
+                    .map_err(|e| format!("route[{}]: {}", i + 1, e))?,
+                None => default_recipients.clone(),
+            };
+            // The folder's remote directory belongs to the folder's server.
+            let (server, remote_dir) = match &route.server {
+                Some(_) => (route.server.clone(), route.remote_dir.clone()),
+                None => (
+                    root.server.clone(),
+                    route.remote_dir.clone().or_else(|| root.remote_dir.clone()),
+                ),
+            };
+            routes.push(Route {
+                rule: Some(rule),
+                recipients,
+                server,
+                remote_dir,
+                cleanup: route.cleanup.over(&config.cleanup),
+            });
+        }
+
+        Ok(Router {
+            routes,
+            fallback: Route {
+                rule: None,
+                recipients: default_recipients,
+                server: root.server.clone(),
+                remote_dir: root.remote_dir.clone(),
+                cleanup: config.cleanup.clone(),
+            },
+        })
+    }
+
+    /// The first route matching `relative`, a path below `watch_dir`.
+    pub fn route(&self, relative: &Path) -> &Route {
+        self.routes
+            .iter()
+            .find(|route| route.matches(relative))
+            .unwrap_or(&self.fallback)
+    }
+}
+
+/// One connection pool per server, shared by every route that uploads to
+/// it.
+pub struct Servers {
+    default: SftpPool,
+    named: BTreeMap<String, SftpPool>,
+}
+
+impl Servers {
+    pub fn new(config: &Config) -> Self {
+        Servers {
+            default: SftpPool::new(config.sftp.clone()),
+            named: config
+                .servers
+                .iter()
+                .map(|(name, server)| (name.clone(), SftpPool::new(server.clone())))
+                .collect(),
+        }
+    }
+
+    /// Where `route` uploads to. Unknown server names are rejected when the
+    /// config is validated, so they fall back to `[sftp]` here.
+    pub fn destination<'a>(&'a self, route: &'a Route) -> Destination<'a> {
+        let pool = route
+            .server
+            .as_ref()
+            .and_then(|name| self.named.get(name))
+            .unwrap_or(&self.default);
+        match &route.remote_dir {
+            Some(dir) => Destination {
+                pool,
+                remote_dir: dir,
+            },
+            None => Destination::new(pool),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{CleanupOverride, OriginalAction, RouteConfig};
+
+    #[test]
+    fn test_first_matching_route_wins() {
+        let config = Config {
+            routes: vec![
+                RouteConfig {
+                    pattern: "clients/acme/**".to_string(),
+                    remote_dir: Some("/upload/acme".to_string()),
+                    ..RouteConfig::default()
+                },
+                RouteConfig {
+                    pattern: "clients".to_string(),
+                    cleanup: CleanupOverride {
+                        original: Some(OriginalAction::Archive),
+                        ..CleanupOverride::default()
+                    },
+                    ..RouteConfig::default()
+                },
+            ],
+            ..Config::default()
+        };
+        let root = WatchRootConfig {
+            remote_dir: Some("/upload".to_string()),
+            ..WatchRootConfig::default()
+        };
+        let router = Router::new(&config, &root, Vec::new()).unwrap();
+
+        let acme = router.route(Path::new("clients/acme/2024/report.csv"));
+        assert_eq!(acme.remote_dir.as_deref(), Some("/upload/acme"));
+
+        let other = router.route(Path::new("clients/globex/report.csv"));
+        assert_eq!(other.remote_dir.as_deref(), Some("/upload"));
+        assert_eq!(other.cleanup.original, OriginalAction::Archive);
+
+        let unrouted = router.route(Path::new("notes/clients.txt"));
+        assert_eq!(unrouted.cleanup.original, OriginalAction::Delete);
+    }
+
+    #[test]
+    fn test_route_cleanup_keeps_global_dry_run_and_passes() {
+        let config = Config {
+            cleanup: CleanupConfig {
+                dry_run: true,
+                secure_delete_passes: 3,
+                archive_by_date: true,
+                ..CleanupConfig::default()
+            },
+            routes: vec![RouteConfig {
+                pattern: "clients".to_string(),
+                cleanup: CleanupOverride {
+                    original: Some(OriginalAction::Archive),
+                    dry_run: Some(false),
+                    secure_delete_passes: Some(1),
+                    ..CleanupOverride::default()
+                },
+                ..RouteConfig::default()
+            }],
+            ..Config::default()
+        };
+        let router = Router::new(&config, &WatchRootConfig::default(), Vec::new()).unwrap();
+
+        let cleanup = &router.route(Path::new("clients/report.csv")).cleanup;
+        assert_eq!(cleanup.original, OriginalAction::Archive);
+        assert!(cleanup.dry_run);
+        assert_eq!(cleanup.secure_delete_passes, 3);
+        assert!(cleanup.archive_by_date);
+    }
+}
@@ -1,26 +1,225 @@
-use ssh2::Session;
+use crate::config::{HostKeyPolicy, ResumeVerify, SftpAuth, SftpConfig, UploadVerify};
+use crate::retry::{backoff_delay, is_retryable, permanent_unless_transient};
+use crate::transfer::{copy_with_progress, format_bytes, sha256_prefix, Progress};
+use base64::{engine::general_purpose, Engine as _};
+use ssh2::{
+    CheckResult, ErrorCode, HashType, KeyboardInteractivePrompt, KnownHostFileKind,
+    KnownHostKeyFormat, OpenFlags, OpenType, Prompt, Session, Sftp,
+};
 use std::{
-    env,
-    fs::File,
-    io::{Read, Write},
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
     net::TcpStream,
-    path::Path,
+    path::{Component, Path, PathBuf},
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
 };
+
+/// Blocking operations on a session give up after this long, so a dead
+/// connection surfaces as an error instead of hanging the caller.
+const SESSION_TIMEOUT_MS: u32 = 30_000;
+const KEEPALIVE_INTERVAL_SECS: u32 = 30;
+const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(5);
+/// `LIBSSH2_FX_NO_SUCH_FILE` and `LIBSSH2_FX_NO_SUCH_PATH`.
+const SFTP_NO_SUCH_FILE: i32 = 2;
+const SFTP_NO_SUCH_PATH: i32 = 10;
+
+/// Long-lived authenticated SFTP sessions shared by every upload.
+///
+/// Up to `sftp.connections` sessions are opened on demand and handed back
+/// to the pool after each successful upload. A session that fails a
+/// liveness check or errors mid-transfer is dropped and replaced by a
+/// fresh connection on the next checkout.
+pub struct SftpPool {
+    config: SftpConfig,
+    state: Mutex<PoolState>,
+    released: Condvar,
+}
+
+struct PoolState {
+    idle: Vec<Connection>,
+    open: usize,
+}
+
+struct Connection {
+    sess: Session,
+    sftp: Sftp,
+}
+
+/// A connection checked out of the pool. Returned to the idle list on drop
+/// unless it was marked broken.
+struct PooledConnection<'a> {
+    pool: &'a SftpPool,
+    conn: Option<Connection>,
+    broken: bool,
+}
+
+impl SftpPool {
+    pub fn new(config: SftpConfig) -> Self {
+        SftpPool {
+            config,
+            state: Mutex::new(PoolState {
+                idle: Vec::new(),
+                open: 0,
+            }),
+            released: Condvar::new(),
+        }
+    }
+
+    pub fn config(&self) -> &SftpConfig {
+        &self.config
+    }
+
+    fn checkout(&self) -> Result<PooledConnection<'_>, Box<dyn std::error::Error>> {
+        let max = self.config.connections.max(1);
+
+        loop {
+            let mut state = self.state.lock().unwrap();
+
+            if let Some(conn) = state.idle.pop() {
+                drop(state);
+                if self.is_alive(&conn) {
+                    return Ok(PooledConnection {
+                        pool: self,
+                        conn: Some(conn),
+                        broken: false,
+                    });
+                }
+                println!("Dropping dead SFTP connection to {}", self.config.host);
+                self.release_slot();
+                continue;
+            }
+
+            if state.open < max {
+                state.open += 1;
+                break;
+            }
+
+            drop(self.released.wait(state).unwrap());
+        }
+
+        match connect(&self.config) {
+            Ok(conn) => Ok(PooledConnection {
+                pool: self,
+                conn: Some(conn),
+                broken: false,
+            }),
+            Err(e) => {
+                self.release_slot();
+                Err(e)
+            }
+        }
+    }
+
+    fn is_alive(&self, conn: &Connection) -> bool {
+        conn.sftp.stat(Path::new(&self.config.remote_dir)).is_ok()
+    }
+
+    fn release_slot(&self) {
+        self.state.lock().unwrap().open -= 1;
+        self.released.notify_one();
+    }
+}
+
+impl PooledConnection<'_> {
+    fn sftp(&self) -> &Sftp {
+        &self.conn.as_ref().unwrap().sftp
+    }
+
+    fn session(&self) -> &Session {
+        &self.conn.as_ref().unwrap().sess
+    }
+}
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        let Some(conn) = self.conn.take() else {
+            return;
+        };
+        if self.broken {
+            drop(conn);
+            self.pool.release_slot();
+        } else {
+            self.pool.state.lock().unwrap().idle.push(conn);
+            self.pool.released.notify_one();
+        }
+    }
+}
+
+fn connect(config: &SftpConfig) -> Result<Connection, Box<dyn std::error::Error>> {
+    let tcp = TcpStream::connect((config.host.as_str(), config.port))?;
+    let mut sess = Session::new()?;
+    sess.set_tcp_stream(tcp);
+    sess.set_timeout(SESSION_TIMEOUT_MS);
+    sess.handshake()?;
+    verify_host_key(&sess, config).map_err(permanent_unless_transient)?;
+    authenticate(&sess, config).map_err(permanent_unless_transient)?;
+    sess.set_keepalive(true, KEEPALIVE_INTERVAL_SECS);
+
+    let sftp = sess.sftp()?;
+    println!("Connected to {}:{}", config.host, config.port);
+
+    Ok(Connection { sess, sftp })
+}
+
+/// A directory on one server that files are uploaded into.
+#[derive(Clone, Copy)]
+pub struct Destination<'a> {
+    pub pool: &'a SftpPool,
+    pub remote_dir: &'a str,
+}
+
+impl<'a> Destination<'a> {
+    /// The remote directory configured for `pool`'s server.
+    pub fn new(pool: &'a SftpPool) -> Self {
+        Destination {
+            pool,
+            remote_dir: &pool.config().remote_dir,
+        }
+    }
+}
+
+/// Uploads and verifies `path`, retrying with exponential backoff. Errors that
+/// another attempt cannot fix, such as a rejected login, are returned at once.
+/// `remote_name` is the destination relative to the remote directory.
+/// `on_uploaded` runs each time the file has been written, before it is verified.
 pub fn upload_file_with_retry(
     path: &str,
-    max_retries: u32,
-    backoff_ms: u64,
+    remote_name: &Path,
+    dest: Destination,
+    on_uploaded: &mut dyn FnMut(),
 ) -> Result<(), Box<dyn std::error::Error>> {
+    let config = dest.pool.config();
+    let max_retries = config.retry;
     for attempt in 1..=max_retries {
-        match upload_file(path) {
+        let result = upload_file(
+            path,
+            remote_name,
+            dest,
+            config.resume,
+            &mut progress_logger(path),
+        )
+        .inspect(|_| on_uploaded())
+        .and_then(|_| verify_upload(path, remote_name, dest));
+        match result {
             Ok(_) => {
                 println!("Upload succeeded on attempt {}", attempt);
                 return Ok(());
             }
+            Err(e) if !is_retryable(e.as_ref()) => {
+                eprintln!("Upload failed (attempt {}), not retrying: {}", attempt, e);
+                return Err(e);
+            }
             Err(e) => {
                 eprintln!("Upload failed (attempt {}): {}", attempt, e);
                 if attempt < max_retries {
-                    std::thread::sleep(std::time::Duration::from_millis(backoff_ms));
+                    let delay = backoff_delay(
+                        attempt,
+                        Duration::from_millis(config.retry_backoff_ms),
+                        Duration::from_millis(config.retry_max_backoff_ms),
+                    );
+                    println!("Retrying in {:.1}s", delay.as_secs_f64());
+                    std::thread::sleep(delay);
                 }
             }
         }
@@ -29,33 +228,648 @@ pub fn upload_file_with_retry(
     Err(format!("All {} upload attempts failed", max_retries).into())
 }
 
-pub fn upload_file(local_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let host = env::var("SFTP_HOST")?;
-    let port = env::var("SFTP_PORT").unwrap_or_else(|_| "22".to_string());
-    let username = env::var("SFTP_USER")?;
-    let password = env::var("SFTP_PASS")?;
-    let remote_dir = env::var("SFTP_REMOTE_DIR")?;
-
-    let tcp = TcpStream::connect(format!("{}:{}", host, port))?;
-    let mut sess = Session::new()?;
-    sess.set_tcp_stream(tcp);
-    sess.handshake()?;
-    sess.userauth_password(&username, &password)?;
-    assert!(sess.authenticated());
-
-    let sftp = sess.sftp()?;
+/// Streams `local_path` to `remote_name` below the remote directory through
+/// a bounded buffer, reporting progress after every chunk. Missing remote
+/// subdirectories are created. The file is written under a temporary name
+/// tied to its contents. With `resume`, a shorter file left there by an
+/// interrupted attempt, in this process or an earlier one, is appended to
+/// instead of rewritten once its contents have been checked.
+pub fn upload_file(
+    local_path: &str,
+    remote_name: &Path,
+    dest: Destination,
+    resume: bool,
+    on_progress: &mut dyn FnMut(&Progress),
+) -> Result<(), Box<dyn std::error::Error>> {
+    let config = dest.pool.config();
+    let mut conn = dest.pool.checkout()?;
 
     let local_file = Path::new(local_path);
     let filename = local_file.file_name().unwrap().to_str().unwrap();
-    let remote_path = Path::new(&remote_dir).join(filename);
+    let remote_path = join_remote(dest.remote_dir, remote_name)?;
+    let remote_filename = remote_name
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or("Remote file name is not valid UTF-8")?;
 
     let mut local = File::open(local_file)?;
-    let mut remote = sftp.create(&remote_path)?;
-    let mut buffer = Vec::new();
-    local.read_to_end(&mut buffer)?;
-    remote.write_all(&buffer)?;
+    let total = local.metadata()?.len();
+    let content_id = content_id(&sha256_prefix(&mut local, total)?);
+    let temp_name = temp_remote_name(
+        remote_filename,
+        &content_id,
+        &config.temp_prefix,
+        &config.temp_suffix,
+    );
+    let temp_path = join_remote(dest.remote_dir, &remote_name.with_file_name(temp_name))?;
+    let (remote_path, temp_path) = (Path::new(&remote_path), Path::new(&temp_path));
+
+    let result = (|| -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(parent) = remote_name.parent() {
+            create_remote_dirs(conn.sftp(), dest.remote_dir, parent)?;
+        }
+
+        let offset = if resume {
+            resume_offset(&conn, temp_path, &mut local, total, config.resume_verify)?
+        } else {
+            0
+        };
+
+        let mut remote = if offset > 0 {
+            println!(
+                "Resuming upload of {} at {} of {}",
+                filename,
+                format_bytes(offset),
+                format_bytes(total)
+            );
+            let mut remote =
+                conn.sftp()
+                    .open_mode(temp_path, OpenFlags::WRITE, 0o644, OpenType::File)?;
+            remote.seek(SeekFrom::Start(offset))?;
+            remote
+        } else {
+            conn.sftp().create(temp_path)?
+        };
+        local.seek(SeekFrom::Start(offset))?;
+
+        copy_with_progress(
+            &mut local,
+            &mut remote,
+            total,
+            offset,
+            config.buffer_size_kb.max(1) * 1024,
+            config.bandwidth_limit_kbps.map(|kbps| kbps * 1024),
+            on_progress,
+        )?;
+        drop(remote);
+
+        let uploaded = conn.sftp().stat(temp_path)?.size.unwrap_or(0);
+        if uploaded != total {
+            return Err(format!(
+                "Remote size of {} is {} bytes, expected {}",
+                temp_path.display(),
+                uploaded,
+                total
+            )
+            .into());
+        }
+
+        rename_into_place(conn.sftp(), temp_path, remote_path)
+    })();
+
+    if let Err(e) = result {
+        conn.broken = true;
+        return Err(e);
+    }
 
     println!("Uploaded {} to {}", filename, remote_path.display());
 
     Ok(())
 }
+
+/// Checks that the uploaded copy of `local_path` matches the local file,
+/// using the configured verification method. The size is always compared.
+pub fn verify_upload(
+    local_path: &str,
+    remote_name: &Path,
+    dest: Destination,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let config = dest.pool.config();
+    let mut conn = dest.pool.checkout()?;
+    let local_file = Path::new(local_path);
+    let remote_path = join_remote(dest.remote_dir, remote_name)?;
+    let remote_path = Path::new(&remote_path);
+
+    let result = (|| -> Result<(), Box<dyn std::error::Error>> {
+        let mut local = File::open(local_file)?;
+        let total = local.metadata()?.len();
+
+        let remote_size = conn.sftp().stat(remote_path)?.size.unwrap_or(0);
+        if remote_size != total {
+            return Err(format!(
+                "Verification failed: {} is {} bytes on the server, {} locally",
+                remote_path.display(),
+                remote_size,
+                total
+            )
+            .into());
+        }
+
+        let remote_hash = match config.verify {
+            UploadVerify::Size => return Ok(()),
+            UploadVerify::Sha256sum => remote_sha256sum(conn.session(), remote_path, None)?,
+            UploadVerify::ReadBack => sha256_prefix(&mut conn.sftp().open(remote_path)?, total)?,
+        };
+        let local_hash = sha256_prefix(&mut local, total)?;
+
+        if remote_hash != local_hash {
+            return Err(format!(
+                "Verification failed: SHA-256 of {} does not match the local file",
+                remote_path.display()
+            )
+            .into());
+        }
+        Ok(())
+    })();
+
+    if result.is_err() {
+        // A failed check may be a dead session; do not hand it out again.
+        conn.broken = true;
+    }
+    result
+}
+
+/// Runs `sha256sum` on the server over an exec channel, over the first
+/// `len` bytes only if given.
+fn remote_sha256sum(
+    sess: &Session,
+    remote_path: &Path,
+    len: Option<u64>,
+) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+    let path = shell_quote(
+        remote_path
+            .to_str()
+            .ok_or("Remote path is not valid UTF-8")?,
+    );
+    let command = match len {
+        Some(len) => format!("head -c {} -- {} | sha256sum", len, path),
+        None => format!("sha256sum -- {}", path),
+    };
+    let mut channel = sess.channel_session()?;
+    channel.exec(&command)?;
+
+    let mut output = String::new();
+    channel.read_to_string(&mut output)?;
+    channel.wait_close()?;
+
+    let status = channel.exit_status()?;
+    if status != 0 {
+        return Err(format!("Remote sha256sum exited with status {}", status).into());
+    }
+    parse_sha256sum(&output)
+        .ok_or_else(|| format!("Unexpected sha256sum output: {}", output.trim()).into())
+}
+
+/// Quotes `value` for a POSIX shell.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+fn parse_sha256sum(output: &str) -> Option<[u8; 32]> {
+    let hex = output.split_whitespace().next()?;
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut digest = [0u8; 32];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(digest)
+}
+
+/// Whether `remote_name` already exists below the remote directory.
+pub fn remote_exists(
+    remote_name: &Path,
+    dest: Destination,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let remote_path = join_remote(dest.remote_dir, remote_name)?;
+    let mut conn = dest.pool.checkout()?;
+    match conn.sftp().stat(Path::new(&remote_path)) {
+        Ok(_) => Ok(true),
+        Err(e)
+            if matches!(
+                e.code(),
+                ErrorCode::SFTP(SFTP_NO_SUCH_FILE | SFTP_NO_SUCH_PATH)
+            ) =>
+        {
+            Ok(false)
+        }
+        Err(e) => {
+            conn.broken = true;
+            Err(e.into())
+        }
+    }
+}
+
+/// Creates each directory of `relative` below `remote_dir` that does not
+/// exist yet.
+fn create_remote_dirs(
+    sftp: &Sftp,
+    remote_dir: &str,
+    relative: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut below = PathBuf::new();
+    for component in relative.components() {
+        below.push(component);
+        let dir = join_remote(remote_dir, &below)?;
+        let dir = Path::new(&dir);
+        if sftp.stat(dir).is_ok() {
+            continue;
+        }
+        if let Err(e) = sftp.mkdir(dir, 0o755) {
+            // Another upload may have created it in the meantime.
+            if sftp.stat(dir).is_err() {
+                return Err(e.into());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The server path of `relative` below `remote_dir`. SFTP paths always use
+/// `/`, so the local separator never ends up in them.
+fn join_remote(remote_dir: &str, relative: &Path) -> Result<String, Box<dyn std::error::Error>> {
+    let mut path = remote_dir.trim_end_matches('/').to_string();
+    for component in relative.components() {
+        match component {
+            Component::Normal(name) => {
+                path.push('/');
+                path.push_str(name.to_str().ok_or("Remote path is not valid UTF-8")?);
+            }
+            Component::CurDir => {}
+            _ => return Err(format!("{} is not a relative path", relative.display()).into()),
+        }
+    }
+    Ok(path)
+}
+
+/// Name under which a file is written until it is complete, e.g.
+/// `.report.csv.pgp.3f2a9c1e0b7d4a55.partial`. `content_id` keeps uploads
+/// of different contents to the same name from sharing a partial file.
+fn temp_remote_name(filename: &str, content_id: &str, prefix: &str, suffix: &str) -> String {
+    format!("{}{}.{}{}", prefix, filename, content_id, suffix)
+}
+
+/// Short hex id for a file with SHA-256 `digest`.
+fn content_id(digest: &[u8; 32]) -> String {
+    digest[..8].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Moves a finished upload to its final name. SFTP v3 servers such as
+/// OpenSSH refuse to rename over an existing file, so an old copy is
+/// removed first when the plain rename fails.
+fn rename_into_place(
+    sftp: &Sftp,
+    temp_path: &Path,
+    remote_path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if sftp.rename(temp_path, remote_path, None).is_ok() {
+        return Ok(());
+    }
+    if sftp.stat(remote_path).is_ok() {
+        sftp.unlink(remote_path)?;
+    }
+    sftp.rename(temp_path, remote_path, None)?;
+    Ok(())
+}
+
+/// How many bytes of `remote_path` can be kept from an earlier attempt.
+/// Only a strictly shorter remote file is resumed, and only if its contents
+/// match the start of the local file, hashed as `verify` says.
+fn resume_offset(
+    conn: &PooledConnection,
+    remote_path: &Path,
+    local: &mut File,
+    total: u64,
+    verify: ResumeVerify,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let remote_size = match conn.sftp().stat(remote_path) {
+        Ok(stat) => stat.size.unwrap_or(0),
+        Err(_) => return Ok(0),
+    };
+
+    if remote_size == 0 || remote_size >= total {
+        return Ok(0);
+    }
+
+    local.seek(SeekFrom::Start(0))?;
+    let local_hash = sha256_prefix(local, remote_size)?;
+    let remote_hash = match verify {
+        ResumeVerify::Sha256sum => {
+            remote_sha256sum(conn.session(), remote_path, Some(remote_size))?
+        }
+        ResumeVerify::ReadBack => sha256_prefix(&mut conn.sftp().open(remote_path)?, remote_size)?,
+    };
+    if local_hash != remote_hash {
+        println!(
+            "Partial upload at {} does not match the local file, restarting",
+            remote_path.display()
+        );
+        return Ok(0);
+    }
+
+    Ok(remote_size)
+}
+
+/// Logs upload progress at most every few seconds, plus once at the end.
+fn progress_logger(path: &str) -> impl FnMut(&Progress) + '_ {
+    let mut last_logged = Instant::now();
+    move |progress: &Progress| {
+        let done = progress.sent >= progress.total;
+        if !done && last_logged.elapsed() < PROGRESS_LOG_INTERVAL {
+            return;
+        }
+        last_logged = Instant::now();
+        println!(
+            "Uploading {}: {} / {} ({:.0}%) at {}/s",
+            path,
+            format_bytes(progress.sent),
+            format_bytes(progress.total),
+            progress.percent(),
+            format_bytes(progress.throughput())
+        );
+    }
+}
+
+/// Checks the server's host key against the pinned fingerprint if one is
+/// configured, otherwise against known_hosts according to the policy.
+fn verify_host_key(sess: &Session, config: &SftpConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let (key, key_type) = sess.host_key().ok_or("Server did not present a host key")?;
+    let fingerprint = sess
+        .host_key_hash(HashType::Sha256)
+        .map(format_fingerprint)
+        .ok_or("Failed to hash the server host key")?;
+
+    if let Some(pinned) = &config.host_key_fingerprint {
+        if normalize_fingerprint(pinned) != normalize_fingerprint(&fingerprint) {
+            return Err(format!(
+                "Host key for {}:{} does not match the pinned fingerprint (expected {}, got {})",
+                config.host, config.port, pinned, fingerprint
+            )
+            .into());
+        }
+        return Ok(());
+    }
+
+    if config.host_key_policy == HostKeyPolicy::Insecure {
+        eprintln!(
+            "WARNING: host key verification disabled, accepting {} for {}:{}",
+            fingerprint, config.host, config.port
+        );
+        return Ok(());
+    }
+
+    let known_hosts_path = config
+        .known_hosts_path()
+        .ok_or("Cannot locate known_hosts; set sftp.known_hosts")?;
+    let mut known_hosts = sess.known_hosts()?;
+    if known_hosts_path.exists() {
+        known_hosts.read_file(&known_hosts_path, KnownHostFileKind::OpenSSH)?;
+    }
+
+    match known_hosts.check_port(&config.host, config.port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => Err(format!(
+            "HOST KEY CHANGED for {}:{}! The server now presents {} which does not match {}. \
+             This could be a man-in-the-middle attack; remove the old entry only if the change is expected.",
+            config.host,
+            config.port,
+            fingerprint,
+            known_hosts_path.display()
+        )
+        .into()),
+        CheckResult::NotFound if config.host_key_policy == HostKeyPolicy::AcceptNew => {
+            let host = known_hosts_entry(&config.host, config.port);
+            append_known_host(sess, &known_hosts_path, &host, key, key_type.into())?;
+            println!(
+                "Added host key {} for {} to {}",
+                fingerprint,
+                host,
+                known_hosts_path.display()
+            );
+            Ok(())
+        }
+        CheckResult::NotFound => Err(format!(
+            "Host {}:{} ({}) is not in {}; add it with ssh-keyscan or use host_key_policy = \"accept-new\"",
+            config.host,
+            config.port,
+            fingerprint,
+            known_hosts_path.display()
+        )
+        .into()),
+        CheckResult::Failure => Err(format!(
+            "Failed to check {} against {}",
+            config.host,
+            known_hosts_path.display()
+        )
+        .into()),
+    }
+}
+
+/// Formats a raw SHA-256 host key hash the way `ssh-keygen -l` prints it.
+fn format_fingerprint(hash: &[u8]) -> String {
+    format!("SHA256:{}", general_purpose::STANDARD_NO_PAD.encode(hash))
+}
+
+fn normalize_fingerprint(fingerprint: &str) -> &str {
+    fingerprint.trim().trim_end_matches('=')
+}
+
+/// Appends one host key line to `path`. Rewriting the whole file from what
+/// libssh2 parsed would drop comments and entries it does not understand,
+/// and would race with other connections adding their own line.
+fn append_known_host(
+    sess: &Session,
+    path: &Path,
+    host: &str,
+    key: &[u8],
+    key_format: KnownHostKeyFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut added = sess.known_hosts()?;
+    added.add(host, key, "added by VaultSync", key_format)?;
+    let mut line = String::new();
+    for entry in added.hosts()? {
+        line.push_str(&added.write_string(&entry, KnownHostFileKind::OpenSSH)?);
+    }
+    if !line.ends_with('\n') {
+        line.push('\n');
+    }
+    let unterminated =
+        fs::read(path).is_ok_and(|existing| existing.last().is_some_and(|&b| b != b'\n'));
+    if unterminated {
+        line.insert(0, '\n');
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+fn known_hosts_entry(host: &str, port: u16) -> String {
+    if port == 22 {
+        host.to_string()
+    } else {
+        format!("[{}]:{}", host, port)
+    }
+}
+
+fn authenticate(sess: &Session, config: &SftpConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let user = config.user.as_str();
+
+    match config.auth {
+        SftpAuth::Password => {
+            let password = config
+                .password
+                .as_deref()
+                .ok_or("SFTP password is not configured")?;
+            sess.userauth_password(user, password)?;
+        }
+        SftpAuth::Key => {
+            let key = config
+                .private_key
+                .as_deref()
+                .ok_or("SFTP private key is not configured")?;
+            sess.userauth_pubkey_file(user, None, key, config.private_key_passphrase.as_deref())?;
+        }
+        SftpAuth::Agent => {
+            let mut agent = sess.agent()?;
+            agent.connect()?;
+            agent.list_identities()?;
+            // Session::userauth_agent only tries the first identity.
+            let accepted = agent
+                .identities()?
+                .iter()
+                .any(|identity| agent.userauth(user, identity).is_ok());
+            agent.disconnect().ok();
+            if !accepted {
+                return Err("No ssh-agent identity was accepted by the server".into());
+            }
+        }
+        SftpAuth::KeyboardInteractive => {
+            let password = config
+                .password
+                .as_deref()
+                .ok_or("SFTP password is not configured")?;
+            sess.userauth_keyboard_interactive(user, &mut PasswordPrompt(password))?;
+        }
+    }
+
+    if !sess.authenticated() {
+        return Err(format!("SFTP authentication as '{}' failed", user).into());
+    }
+
+    Ok(())
+}
+
+/// Answers every keyboard-interactive prompt with the configured password.
+struct PasswordPrompt<'a>(&'a str);
+
+impl KeyboardInteractivePrompt for PasswordPrompt<'_> {
+    fn prompt<'b>(
+        &mut self,
+        _username: &str,
+        _instructions: &str,
+        prompts: &[Prompt<'b>],
+    ) -> Vec<String> {
+        prompts.iter().map(|_| self.0.to_string()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_fingerprint_matches_openssh() {
+        let hash = [0u8; 32];
+        assert_eq!(
+            format_fingerprint(&hash),
+            "SHA256:AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA"
+        );
+        assert_eq!(
+            normalize_fingerprint("SHA256:abc= "),
+            normalize_fingerprint("SHA256:abc")
+        );
+    }
+
+    #[test]
+    fn test_failed_connect_releases_pool_slot() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config = SftpConfig {
+            host: "127.0.0.1".to_string(),
+            port,
+            connections: 1,
+            ..SftpConfig::default()
+        };
+        let pool = SftpPool::new(config);
+
+        // With a single slot, a leaked slot would make the second call block.
+        assert!(pool.checkout().is_err());
+        assert!(pool.checkout().is_err());
+        assert_eq!(pool.state.lock().unwrap().open, 0);
+    }
+
+    #[test]
+    fn test_temp_remote_name() {
+        let id = content_id(&[0xab; 32]);
+        assert_eq!(id, "abababababababab");
+        assert_eq!(
+            temp_remote_name("report.csv.pgp", &id, ".", ".partial"),
+            ".report.csv.pgp.abababababababab.partial"
+        );
+        assert_eq!(temp_remote_name("a.pgp", "01", "", ".tmp"), "a.pgp.01.tmp");
+    }
+
+    #[test]
+    fn test_remote_paths_use_forward_slashes() {
+        let relative: PathBuf = ["clients", "acme", "report.pgp"].iter().collect();
+        assert_eq!(
+            join_remote("/incoming/", &relative).unwrap(),
+            "/incoming/clients/acme/report.pgp"
+        );
+        assert_eq!(join_remote("/", Path::new("./a.pgp")).unwrap(), "/a.pgp");
+        assert!(join_remote("/incoming", Path::new("../a.pgp")).is_err());
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("/srv/in box/a.pgp"), "'/srv/in box/a.pgp'");
+        assert_eq!(shell_quote("it's.pgp"), "'it'\\''s.pgp'");
+    }
+
+    #[test]
+    fn test_parse_sha256sum() {
+        let output = format!("{}  /srv/in/a.pgp\n", "ab".repeat(32));
+        assert_eq!(parse_sha256sum(&output), Some([0xab; 32]));
+        assert_eq!(parse_sha256sum("sha256sum: a.pgp: No such file"), None);
+    }
+
+    #[test]
+    fn test_known_hosts_entry_brackets_non_default_port() {
+        assert_eq!(known_hosts_entry("example.com", 22), "example.com");
+        assert_eq!(known_hosts_entry("example.com", 2222), "[example.com]:2222");
+    }
+
+    #[test]
+    fn test_new_host_key_is_appended() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("known_hosts");
+        let existing = "# managed by hand\nold.example ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIA==";
+        fs::write(&path, existing).unwrap();
+
+        let sess = Session::new().unwrap();
+        let key = [7u8; 51];
+        append_known_host(
+            &sess,
+            &path,
+            "[new.example]:2222",
+            &key,
+            KnownHostKeyFormat::Ed25519,
+        )
+        .unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let (before, added) = contents.split_at(existing.len() + 1);
+        assert_eq!(before, format!("{}\n", existing));
+        assert!(added.starts_with("[new.example]:2222 ssh-ed25519 "));
+        assert!(added.ends_with('\n'));
+        assert_eq!(added.lines().count(), 1);
+    }
+}
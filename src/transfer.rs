@@ -0,0 +1,201 @@
+use sha2::{Digest, Sha256};
+use std::{
+    io::{self, Read, Write},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Snapshot of a running transfer handed to progress callbacks.
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    /// Bytes present at the destination, including any resumed prefix.
+    pub sent: u64,
+    pub total: u64,
+    /// Bytes that were already at the destination when the transfer started.
+    pub offset: u64,
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    /// Average bytes per second moved by this transfer since it started.
+    pub fn throughput(&self) -> u64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            ((self.sent - self.offset) as f64 / secs) as u64
+        } else {
+            0
+        }
+    }
+
+    pub fn percent(&self) -> f64 {
+        if self.total == 0 {
+            100.0
+        } else {
+            self.sent as f64 * 100.0 / self.total as f64
+        }
+    }
+}
+
+/// Copies `reader` into `writer` through a fixed-size buffer, calling
+/// `on_progress` after every chunk and sleeping as needed to stay under
+/// `limit_bytes_per_sec`. `offset` is the number of bytes the destination
+/// already holds; both streams must be positioned past it. Returns the
+/// number of bytes copied by this call.
+pub fn copy_with_progress<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    total: u64,
+    offset: u64,
+    buffer_size: usize,
+    limit_bytes_per_sec: Option<u64>,
+    on_progress: &mut dyn FnMut(&Progress),
+) -> io::Result<u64> {
+    let mut buffer = vec![0u8; buffer_size.max(1)];
+    let started = Instant::now();
+    let mut sent = 0u64;
+
+    loop {
+        let n = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buffer[..n])?;
+        sent += n as u64;
+
+        if let Some(limit) = limit_bytes_per_sec.filter(|l| *l > 0) {
+            let due = Duration::from_secs_f64(sent as f64 / limit as f64);
+            let elapsed = started.elapsed();
+            if due > elapsed {
+                thread::sleep(due - elapsed);
+            }
+        }
+
+        on_progress(&Progress {
+            sent: offset + sent,
+            total,
+            offset,
+            elapsed: started.elapsed(),
+        });
+    }
+
+    writer.flush()?;
+    Ok(sent)
+}
+
+/// SHA-256 of the first `len` bytes of `reader`. Fails if the stream is
+/// shorter than `len`.
+pub fn sha256_prefix<R: Read>(reader: &mut R, len: u64) -> io::Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    let copied = io::copy(&mut reader.take(len), &mut hasher)?;
+    if copied != len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("expected {} bytes, read {}", len, copied),
+        ));
+    }
+    Ok(hasher.finalize().into())
+}
+
+/// Formats a byte count with a binary unit suffix, e.g. `1.5 MiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_copy_reports_every_chunk() {
+        let data = vec![7u8; 10_000];
+        let mut out = Vec::new();
+        let mut reports = Vec::new();
+
+        let copied = copy_with_progress(
+            &mut data.as_slice(),
+            &mut out,
+            data.len() as u64,
+            0,
+            4096,
+            None,
+            &mut |p| reports.push(p.sent),
+        )
+        .unwrap();
+
+        assert_eq!(copied, 10_000);
+        assert_eq!(out, data);
+        assert_eq!(reports, vec![4096, 8192, 10_000]);
+    }
+
+    #[test]
+    fn test_copy_respects_bandwidth_limit() {
+        let data = vec![0u8; 2048];
+        let started = Instant::now();
+
+        copy_with_progress(
+            &mut data.as_slice(),
+            &mut io::sink(),
+            data.len() as u64,
+            0,
+            512,
+            Some(10 * 1024),
+            &mut |_| {},
+        )
+        .unwrap();
+
+        // 2 KiB at 10 KiB/s takes about 200ms.
+        assert!(started.elapsed() >= Duration::from_millis(180));
+    }
+
+    #[test]
+    fn test_copy_from_offset_counts_resumed_prefix() {
+        let rest = vec![1u8; 100];
+        let mut last = None;
+
+        copy_with_progress(
+            &mut rest.as_slice(),
+            &mut io::sink(),
+            150,
+            50,
+            64,
+            None,
+            &mut |p| last = Some(*p),
+        )
+        .unwrap();
+
+        let last = last.unwrap();
+        assert_eq!(last.sent, 150);
+        assert_eq!(last.offset, 50);
+        assert_eq!(last.percent(), 100.0);
+    }
+
+    #[test]
+    fn test_sha256_prefix() {
+        let data = b"abcdef";
+        assert_eq!(
+            sha256_prefix(&mut data.as_slice(), 3).unwrap(),
+            sha256_prefix(&mut b"abc".as_slice(), 3).unwrap()
+        );
+        assert!(sha256_prefix(&mut data.as_slice(), 10).is_err());
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+}
@@ -1,41 +1,191 @@
-use crate::{config, pgp::encrypt_file_with_pgp, sftp::upload_file_with_retry};
+use crate::{
+    cleanup::{finish_encrypted, finish_original},
+    collision::resolve_name,
+    config::{Config, WatchRootConfig},
+    debounce::Debouncer,
+    filter::FileFilter,
+    pgp::{encrypt_file_to, signature_path, write_detached_signature},
+    queue::{Job, JobQueue, Stage},
+    retry::backoff_delay,
+    routing::{Router, Servers},
+    semaphore::Semaphore,
+    sftp::{remote_exists, upload_file_with_retry, verify_upload},
+};
 
 use notify::{
-    event::{EventKind, ModifyKind},
+    event::{AccessKind, AccessMode, EventKind, ModifyKind},
     recommended_watcher, Event, RecursiveMode, Result, Watcher,
 };
-use sequoia_openpgp::Cert;
+use sequoia_openpgp::{crypto::KeyPair, Cert};
 
 use std::{
-    fs,
+    collections::HashSet,
+    fs, io,
     path::{Path, PathBuf},
     sync::{
         atomic::{AtomicBool, Ordering},
-        mpsc, Arc,
+        mpsc::{self, Receiver, SyncSender},
+        Arc, Mutex,
     },
-    time::Duration,
+    thread,
+    time::{Duration, Instant},
 };
 
-pub fn start_watching(path: &str, shutdown: Arc<AtomicBool>, cert: Cert) -> Result<()> {
+/// How often due jobs are handed to the workers when nothing new was queued.
+const DISPATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// One watched folder with the filter, output directory and routes that
+/// apply to files below it.
+pub struct Root {
+    /// Absolute, so that it prefixes the paths reported by notify.
+    dir: PathBuf,
+    encrypted_dir: PathBuf,
+    filter: FileFilter,
+    router: Router,
+}
+
+impl Root {
+    pub fn new(
+        config: &Config,
+        root: &WatchRootConfig,
+        default_recipients: Vec<Cert>,
+    ) -> std::result::Result<Self, Box<dyn std::error::Error>> {
+        Ok(Root {
+            dir: absolute(&root.dir),
+            encrypted_dir: config.root_encrypted_dir(root),
+            filter: FileFilter::new(&root.dir, &config.filter)?,
+            router: Router::new(config, root, default_recipients)?,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Directory of `source` relative to this folder, mirrored below the
+    /// encrypted and remote directories.
+    fn relative_dir(&self, source: &Path) -> PathBuf {
+        source
+            .strip_prefix(&self.dir)
+            .ok()
+            .and_then(Path::parent)
+            .map(Path::to_path_buf)
+            .unwrap_or_default()
+    }
+}
+
+/// Shared state handed to every worker.
+struct Pipeline<'a> {
+    queue: &'a JobQueue,
+    roots: &'a [Root],
+    config: &'a Config,
+    servers: &'a Servers,
+    /// Signs every file before it is encrypted, if configured.
+    signer: Option<&'a KeyPair>,
+    encrypt_slots: Semaphore,
+    upload_slots: Semaphore,
+    /// Sources of jobs currently held by a worker.
+    in_flight: Mutex<HashSet<PathBuf>>,
+    /// Our own output and state folders, which may sit inside a watched
+    /// folder but must never be processed.
+    skip: Vec<PathBuf>,
+}
+
+impl Pipeline<'_> {
+    /// The watched folder containing `path`. Folders never overlap.
+    fn root_for(&self, path: &Path) -> Option<&Root> {
+        self.roots.iter().find(|root| path.starts_with(&root.dir))
+    }
+
+    fn is_skipped(&self, path: &Path) -> bool {
+        self.skip.iter().any(|dir| path.starts_with(dir))
+    }
+}
+
+pub fn start_watching(
+    config: &Config,
+    servers: &Servers,
+    signer: Option<&KeyPair>,
+    shutdown: Arc<AtomicBool>,
+    roots: Vec<Root>,
+) -> Result<()> {
+    let queue = JobQueue::open(&config.state_dir)?;
+    let pending = queue.jobs().len();
+    if pending > 0 {
+        println!("Resuming {} unfinished job(s)", pending);
+    }
+
+    let pipeline = Pipeline {
+        queue: &queue,
+        roots: &roots,
+        config,
+        servers,
+        signer,
+        encrypt_slots: Semaphore::new(config.workers.encrypt_concurrency),
+        upload_slots: Semaphore::new(config.workers.upload_concurrency),
+        in_flight: Mutex::new(HashSet::new()),
+        skip: skipped_dirs(config, &roots),
+    };
+    let (job_tx, job_rx) = mpsc::sync_channel::<Job>(config.workers.queue_capacity);
+    let job_rx = Mutex::new(job_rx);
+
+    // Workers exit once the watch loop drops the sender; the scope waits
+    // for their current jobs to finish.
+    thread::scope(|scope| {
+        for _ in 0..config.workers.count {
+            scope.spawn(|| run_worker(&job_rx, &pipeline, &shutdown));
+        }
+        watch_loop(&pipeline, job_tx, &shutdown)
+    })
+}
+
+fn watch_loop(pipeline: &Pipeline, job_tx: SyncSender<Job>, shutdown: &AtomicBool) -> Result<()> {
+    let config = pipeline.config;
+
     let (tx, rx) = mpsc::channel::<Result<Event>>();
     let mut watcher = recommended_watcher(tx)?;
-    watcher.watch(Path::new(path), RecursiveMode::Recursive)?;
+    for root in pipeline.roots {
+        watcher.watch(&root.dir, RecursiveMode::Recursive)?;
+    }
+
+    let mut debouncer = Debouncer::new(Duration::from_millis(config.watch.quiet_period_ms));
+
+    // Pick up files dropped while VaultSync was not running. The watch is
+    // already active, so nothing created during the scan is missed.
+    for path in scan_watch_dir(pipeline) {
+        debouncer.touch(&path, Instant::now());
+    }
+    let scan_interval = Duration::from_secs(config.watch.scan_interval_secs);
+    let mut last_scan = Instant::now();
+    let mut last_dispatch = None::<Instant>;
 
     while !shutdown.load(Ordering::Relaxed) {
+        let mut queued = false;
+
         match rx.recv_timeout(Duration::from_secs(1)) {
             Ok(Ok(event)) => {
                 println!("Raw Event: {:?}", event);
 
-                if matches!(
+                let closed_after_write =
+                    event.kind == EventKind::Access(AccessKind::Close(AccessMode::Write));
+                if closed_after_write && config.watch.close_write {
+                    // The writer is done; no need to wait for the quiet period.
+                    for path in event.paths {
+                        debouncer.forget(&path);
+                        queued |= enqueue(pipeline, &path);
+                    }
+                } else if matches!(
                     event.kind,
                     EventKind::Create(_)
                         | EventKind::Modify(ModifyKind::Data(_))
                         | EventKind::Modify(ModifyKind::Name(_))
                 ) {
                     for path in event.paths {
-                        if should_process(&path) {
-                            println!("Processing: {:?}", path);
-                            handle_file(&path, &cert);
+                        let wanted = pipeline
+                            .root_for(&path)
+                            .is_some_and(|root| root.filter.matches_path(&path));
+                        if should_process(&path) && wanted && !pipeline.is_skipped(&path) {
+                            debouncer.touch(&path, Instant::now());
                         }
                     }
                 }
@@ -47,61 +197,377 @@ pub fn start_watching(path: &str, shutdown: Arc<AtomicBool>, cert: Cert) -> Resu
                 break;
             }
         }
+
+        // Events can be lost, e.g. on network filesystems or when the
+        // kernel queue overflows.
+        if !scan_interval.is_zero() && last_scan.elapsed() >= scan_interval {
+            for path in scan_watch_dir(pipeline) {
+                debouncer.touch(&path, Instant::now());
+            }
+            last_scan = Instant::now();
+        }
+
+        for path in debouncer.take_stable(Instant::now()) {
+            queued |= enqueue(pipeline, &path);
+        }
+
+        if queued || last_dispatch.is_none_or(|t| t.elapsed() >= DISPATCH_INTERVAL) {
+            dispatch_due_jobs(pipeline, &job_tx);
+            last_dispatch = Some(Instant::now());
+        }
     }
 
     println!("Watcher shutting down.");
     Ok(())
 }
 
-fn should_process(path: &PathBuf) -> bool {
-    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
-        ext != "vault"
-    } else {
-        true
+/// Records a job for `path`. Returns whether a new job was created.
+fn enqueue(pipeline: &Pipeline, path: &Path) -> bool {
+    let Some(root) = pipeline.root_for(path) else {
+        return false;
+    };
+    if !should_process(path) || pipeline.is_skipped(path) || !root.filter.accepts(path) {
+        return false;
+    }
+    match pipeline.queue.enqueue(path) {
+        Ok(Some(_)) => {
+            println!("Queued: {:?}", path);
+            true
+        }
+        Ok(None) => false,
+        Err(e) => {
+            eprintln!("Failed to queue {:?}: {}", path, e);
+            false
+        }
     }
 }
 
-fn handle_file(path: &PathBuf, cert: &Cert) {
-    let path_str = path.to_string_lossy();
+/// The state folder and every root's encrypted output folder.
+fn skipped_dirs(config: &Config, roots: &[Root]) -> Vec<PathBuf> {
+    let mut skip = vec![absolute(&config.state_dir)];
+    skip.extend(roots.iter().map(|root| absolute(&root.encrypted_dir)));
+    skip
+}
 
-    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
-        if ext == "pgp" {
+/// Every eligible file currently under the watched folders.
+fn scan_watch_dir(pipeline: &Pipeline) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for root in pipeline.roots {
+        let start = files.len();
+        find_files(&root.dir, &pipeline.skip, &mut files);
+        let mut index = 0;
+        files.retain(|path| {
+            index += 1;
+            index <= start || (should_process(path) && root.filter.matches_path(path))
+        });
+    }
+    files
+}
+
+/// Collects regular files below `dir`, not descending into `skip`.
+fn find_files(dir: &Path, skip: &[PathBuf], files: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Failed to scan {}: {}", dir.display(), e);
             return;
         }
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(kind) if kind.is_dir() && !skip.contains(&path) => find_files(&path, skip, files),
+            Ok(kind) if kind.is_file() => files.push(path),
+            _ => {}
+        }
     }
+}
 
-    println!("Processing: {:?}", path_str);
-    let file_name = path.file_name().unwrap().to_str().unwrap();
-    let output_path = config::encrypted_output_dir().join(format!("{}.pgp", file_name));
+/// Absolute form of a configured directory, so that scanned files get the
+/// same job as the paths reported by notify.
+fn absolute(dir: &Path) -> PathBuf {
+    std::path::absolute(dir).unwrap_or_else(|_| dir.to_path_buf())
+}
 
-    if let Err(e) = fs::create_dir_all(config::encrypted_output_dir()) {
-        eprintln!("Failed to create encrypted output dir: {:?}", e);
-        return;
+fn should_process(path: &Path) -> bool {
+    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+        !matches!(ext, "vault" | "pgp" | "sig")
+    } else {
+        true
     }
+}
 
-    if let Err(e) = encrypt_file_with_pgp(&path_str, cert) {
-        eprintln!("PGP encryption failed: {:?}", e);
-        return;
+/// Hands due jobs to the workers. Jobs stay in the journal until they
+/// finish, so when the hand-off queue is full the rest simply wait for a
+/// later pass instead of blocking the watcher.
+fn dispatch_due_jobs(pipeline: &Pipeline, job_tx: &SyncSender<Job>) {
+    // Held for the whole pass so that a worker cannot finish a job between
+    // the queue being read and the in-flight check.
+    let mut in_flight = pipeline.in_flight.lock().unwrap();
+    for job in pipeline.queue.jobs().into_iter().filter(Job::is_due) {
+        if in_flight.contains(&job.source) {
+            continue;
+        }
+        let source = job.source.clone();
+        if job_tx.try_send(job).is_err() {
+            break;
+        }
+        in_flight.insert(source);
     }
+}
 
-    if !output_path.exists() {
-        eprintln!("Encrypted file not found: {}", output_path.display());
-        return;
+fn run_worker(jobs: &Mutex<Receiver<Job>>, pipeline: &Pipeline, shutdown: &AtomicBool) {
+    loop {
+        let received = jobs.lock().unwrap().recv();
+        let Ok(job) = received else {
+            return;
+        };
+        let source = job.source.clone();
+        // After Ctrl+C, jobs still waiting in the hand-off queue are left in
+        // the journal for the next start.
+        if !shutdown.load(Ordering::Relaxed) {
+            run_job(job, pipeline);
+        }
+        pipeline.in_flight.lock().unwrap().remove(&source);
+    }
+}
+
+/// Runs `job` to completion. Finished jobs leave the journal; failed ones
+/// stay at their last completed stage and are retried after
+/// `queue.retry_interval_secs`, doubling with each failure.
+fn run_job(mut job: Job, pipeline: &Pipeline) {
+    let config = pipeline.config;
+    let result = match handle_file(&mut job, pipeline) {
+        Ok(()) => pipeline.queue.remove(&job),
+        Err(e) => {
+            let delay = backoff_delay(
+                job.failures + 1,
+                Duration::from_secs(config.queue.retry_interval_secs),
+                Duration::from_secs(config.queue.retry_max_interval_secs),
+            );
+            eprintln!(
+                "Job for {} failed after stage {:?}, retrying in {}s: {}",
+                job.source.display(),
+                job.stage,
+                delay.as_secs(),
+                e
+            );
+            job.fail(&e.to_string(), delay);
+            pipeline.queue.save(&job)
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("Failed to update job for {}: {}", job.source.display(), e);
     }
+}
+
+/// Moves `job` through the remaining stages, recording each one as it
+/// completes.
+fn handle_file(
+    job: &mut Job,
+    pipeline: &Pipeline,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let (queue, config) = (pipeline.queue, pipeline.config);
+    let Some(root) = pipeline.root_for(&job.source) else {
+        println!(
+            "{} is no longer inside a watched folder, dropping job",
+            job.source.display()
+        );
+        return Ok(());
+    };
+    let relative = root.relative_dir(&job.source);
+    let file_name = job
+        .source
+        .file_name()
+        .unwrap()
+        .to_string_lossy()
+        .into_owned();
+    let route = root.router.route(&relative.join(&file_name));
+    let dest = pipeline.servers.destination(route);
+    loop {
+        match job.stage {
+            Stage::Detected => {
+                if !job.source.exists() {
+                    println!("{} no longer exists, dropping job", job.source.display());
+                    return Ok(());
+                }
+                let _permit = pipeline.encrypt_slots.acquire();
+                println!("Processing: {:?}", job.source);
+
+                // A retried job keeps the name picked the first time; what is
+                // there now may be its own leftover.
+                let output_path = match job.encrypted.clone() {
+                    Some(path) => path,
+                    None => {
+                        let output_dir = root.encrypted_dir.join(&relative);
+                        // The encrypted copy keeps the chosen name on the
+                        // server too, so both destinations are checked.
+                        let chosen = resolve_name(
+                            &format!("{}.pgp", file_name),
+                            config.collision,
+                            &job.source,
+                            &mut |name| {
+                                Ok(output_dir.join(name).exists()
+                                    || remote_exists(&relative.join(name), dest)?)
+                            },
+                        )?;
+                        let Some(name) = chosen else {
+                            println!(
+                                "Skipping {}: {}.pgp already exists at the destination, leaving the original in place",
+                                job.source.display(),
+                                file_name
+                            );
+                            // Not queued again until the original changes.
+                            queue.mark_done(&job.source)?;
+                            return Ok(());
+                        };
+                        let path = output_dir.join(name);
+                        job.encrypted = Some(path.clone());
+                        queue.save(job)?;
+                        path
+                    }
+                };
 
-    let (retry_count, backoff_ms) = config::load_sftp_retry_config();
-    match upload_file_with_retry(output_path.to_str().unwrap(), retry_count, backoff_ms) {
-        Ok(_) => {
-            if let Err(e) = fs::remove_file(path) {
-                eprintln!("Failed to delete original file: {} — {}", path_str, e);
-            } else {
-                println!("Deleted original: {}", path_str);
+                job.record_source();
+                encrypt_file_to(
+                    &job.source.to_string_lossy(),
+                    &route.recipients,
+                    pipeline.signer,
+                    &output_path,
+                )?;
+                write_detached_signature(&config.pgp, pipeline.signer, &job.source, &output_path)?;
+                advance(job, Stage::Encrypted, queue)?;
             }
+            Stage::Encrypted | Stage::Uploaded => {
+                let (encrypted, remote_name) = match &job.encrypted {
+                    Some(path) if path.exists() => (
+                        path.to_string_lossy().into_owned(),
+                        relative.join(path.file_name().unwrap()),
+                    ),
+                    _ => {
+                        println!(
+                            "Encrypted copy of {} is missing, encrypting again",
+                            job.source.display()
+                        );
+                        job.stage = Stage::Detected;
+                        continue;
+                    }
+                };
+
+                let _permit = pipeline.upload_slots.acquire();
+                // A job interrupted between upload and verification only needs verifying.
+                if job.stage == Stage::Uploaded {
+                    if let Err(e) = verify_upload(&encrypted, &remote_name, dest) {
+                        eprintln!(
+                            "Uploaded copy of {} did not verify, uploading again: {}",
+                            encrypted, e
+                        );
+                        job.stage = Stage::Encrypted;
+                        continue;
+                    }
+                } else {
+                    // The signature goes first so it is already there when
+                    // the `.pgp` appears.
+                    let signature = signature_path(Path::new(&encrypted));
+                    if signature.exists() {
+                        let remote_signature = signature_path(&remote_name);
+                        let signature = signature.to_string_lossy();
+                        upload_file_with_retry(&signature, &remote_signature, dest, &mut || {})?;
+                        verify_upload(&signature, &remote_signature, dest)?;
+                    }
+                    upload_file_with_retry(&encrypted, &remote_name, dest, &mut || {
+                        if let Err(e) = advance(job, Stage::Uploaded, queue) {
+                            eprintln!("Failed to record upload of {}: {}", encrypted, e);
+                        }
+                    })?;
+                }
+                advance(job, Stage::Verified, queue)?;
+            }
+            Stage::Verified => {
+                let cleanup = &route.cleanup;
+                // A rewrite while the job was in flight was not queued, so
+                // the new version must not be cleaned up unsent.
+                let changed = job.source_changed();
+                if !changed && finish_original(&job.source, &relative, cleanup)? {
+                    queue.mark_done(&job.source)?;
+                }
+                if let Some(encrypted) = &job.encrypted {
+                    finish_encrypted(encrypted, &relative, cleanup)?;
+                }
+                if changed {
+                    println!(
+                        "{} changed after it was encrypted, processing it again",
+                        job.source.display()
+                    );
+                    job.encrypted = None;
+                    job.source_fingerprint = None;
+                    advance(job, Stage::Detected, queue)?;
+                    continue;
+                }
+                advance(job, Stage::CleanedUp, queue)?;
+            }
+            Stage::CleanedUp => return Ok(()),
         }
-        Err(e) => {
-            eprintln!("Upload failed: {:?}", e);
+    }
+}
+
+fn advance(job: &mut Job, stage: Stage, queue: &JobQueue) -> io::Result<()> {
+    job.stage = stage;
+    queue.save(job)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_own_output_is_never_processed() {
+        assert!(should_process(Path::new("/watch/report.csv")));
+        assert!(should_process(Path::new("/watch/README")));
+        for name in ["report.csv.pgp", "report.csv.pgp.sig", "old.vault"] {
+            assert!(!should_process(&Path::new("/watch").join(name)), "{}", name);
         }
     }
+
+    #[test]
+    fn test_find_files_recurses_and_skips_output_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("clients/acme")).unwrap();
+        fs::create_dir_all(root.join("encrypted")).unwrap();
+        fs::write(root.join("top.txt"), "a").unwrap();
+        fs::write(root.join("clients/acme/deep.txt"), "b").unwrap();
+        fs::write(root.join("encrypted/top.txt.pgp"), "c").unwrap();
+
+        let mut files = Vec::new();
+        find_files(root, &[root.join("encrypted")], &mut files);
+        files.sort();
+
+        assert_eq!(
+            files,
+            vec![root.join("clients/acme/deep.txt"), root.join("top.txt")]
+        );
+    }
+
+    #[test]
+    fn test_relative_dir_mirrors_watch_tree() {
+        let config = Config::default();
+        let root_config = WatchRootConfig {
+            dir: PathBuf::from("/watch"),
+            ..WatchRootConfig::default()
+        };
+        let root = Root::new(&config, &root_config, Vec::new()).unwrap();
+
+        assert_eq!(
+            root.relative_dir(Path::new("/watch/a/b/report.csv")),
+            PathBuf::from("a/b")
+        );
+        assert_eq!(
+            root.relative_dir(Path::new("/watch/report.csv")),
+            PathBuf::new()
+        );
+    }
 }
 
 // #[cfg(test)]
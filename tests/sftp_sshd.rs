@@ -0,0 +1,343 @@
+//! Runs `vault_sync upload` against a throwaway OpenSSH `sshd` listening on
+//! localhost. Skipped when no `sshd` binary is available; point
+//! `VAULTSYNC_TEST_SSHD` at one to run it.
+
+use std::{
+    env, fs,
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use tempfile::TempDir;
+
+struct Sshd {
+    child: Child,
+    port: u16,
+}
+
+impl Drop for Sshd {
+    fn drop(&mut self) {
+        self.child.kill().ok();
+        self.child.wait().ok();
+    }
+}
+
+fn find_sshd() -> Option<PathBuf> {
+    env::var("VAULTSYNC_TEST_SSHD")
+        .ok()
+        .map(PathBuf::from)
+        .into_iter()
+        .chain(["/usr/sbin/sshd", "/usr/bin/sshd", "/usr/local/sbin/sshd"].map(PathBuf::from))
+        .find(|p| p.is_file())
+}
+
+fn current_user() -> String {
+    let output = Command::new("id")
+        .arg("-un")
+        .output()
+        .expect("id -un failed");
+    String::from_utf8(output.stdout).unwrap().trim().to_string()
+}
+
+fn ssh_keygen(path: &Path, key_type: &str, passphrase: &str) {
+    let status = Command::new("ssh-keygen")
+        .args(["-q", "-m", "PEM", "-t", key_type, "-N", passphrase, "-f"])
+        .arg(path)
+        .status()
+        .expect("ssh-keygen not found");
+    assert!(status.success(), "ssh-keygen failed for {}", path.display());
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// A throwaway sshd on localhost that accepts `client_key`, with everything
+/// it needs inside a temp dir.
+struct Fixture {
+    dir: TempDir,
+    client_key: PathBuf,
+    remote_dir: PathBuf,
+    sshd: Sshd,
+}
+
+/// How `vault_sync` logs in to the fixture.
+enum Auth<'a> {
+    Key(&'a Path),
+    Password(&'a str),
+}
+
+impl Fixture {
+    /// Generates a host key and a client key protected by `key_passphrase`
+    /// and starts sshd. `None` if no sshd binary is available.
+    fn start(key_passphrase: &str) -> Option<Fixture> {
+        let Some(sshd_bin) = find_sshd() else {
+            eprintln!("sshd not found, skipping (set VAULTSYNC_TEST_SSHD to run)");
+            return None;
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let client_key = dir.path().join("client_key");
+        ssh_keygen(&client_key, "rsa", key_passphrase);
+        let authorized_keys = dir.path().join("authorized_keys");
+        fs::copy(client_key.with_extension("pub"), &authorized_keys).unwrap();
+        let remote_dir = dir.path().join("remote");
+        fs::create_dir(&remote_dir).unwrap();
+
+        let sshd = start_sshd(&sshd_bin, dir.path(), &authorized_keys);
+        Some(Fixture {
+            dir,
+            client_key,
+            remote_dir,
+            sshd,
+        })
+    }
+
+    fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Writes `contents` to `name` in the temp dir.
+    fn local_file(&self, name: &str, contents: &str) -> PathBuf {
+        let path = self.path().join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /// `vault_sync upload <files>` against the sshd, using a known_hosts
+    /// file inside the temp dir.
+    fn upload(&self, auth: Auth, files: &[PathBuf]) -> Command {
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_vault_sync"));
+        cmd.current_dir(self.path());
+        for (name, _) in env::vars() {
+            if name.starts_with("SFTP_") || name == "VAULTSYNC_CONFIG" {
+                cmd.env_remove(name);
+            }
+        }
+        cmd.arg("upload")
+            .args(files)
+            .args(["--sftp-host", "127.0.0.1"])
+            .args(["--sftp-port", &self.sshd.port.to_string()])
+            .args(["--sftp-user", &current_user()])
+            .arg("--sftp-known-hosts")
+            .arg(self.path().join("known_hosts"))
+            .arg("--sftp-remote-dir")
+            .arg(&self.remote_dir)
+            .args(["--sftp-retry", "1"]);
+        match auth {
+            Auth::Key(key) => {
+                cmd.args(["--sftp-auth", "key"])
+                    .arg("--sftp-private-key")
+                    .arg(key);
+            }
+            Auth::Password(password) => {
+                cmd.args(["--sftp-auth", "password"])
+                    .env("SFTP_PASS", password);
+            }
+        }
+        cmd
+    }
+}
+
+fn start_sshd(sshd: &Path, dir: &Path, authorized_keys: &Path) -> Sshd {
+    let host_key = dir.join("host_key");
+    ssh_keygen(&host_key, "ecdsa", "");
+
+    let port = free_port();
+    let config = dir.join("sshd_config");
+    fs::write(
+        &config,
+        format!(
+            "Port {port}\n\
+             ListenAddress 127.0.0.1\n\
+             HostKey {host_key}\n\
+             PidFile {pid}\n\
+             AuthorizedKeysFile {authorized_keys}\n\
+             PasswordAuthentication yes\n\
+             KbdInteractiveAuthentication no\n\
+             PubkeyAuthentication yes\n\
+             PermitRootLogin yes\n\
+             StrictModes no\n\
+             UsePAM no\n\
+             Subsystem sftp internal-sftp\n",
+            host_key = host_key.display(),
+            pid = dir.join("sshd.pid").display(),
+            authorized_keys = authorized_keys.display(),
+        ),
+    )
+    .unwrap();
+
+    let child = Command::new(sshd)
+        .args(["-D", "-e", "-f"])
+        .arg(&config)
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("failed to start sshd");
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        assert!(Instant::now() < deadline, "sshd did not start listening");
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    Sshd { child, port }
+}
+
+#[test]
+fn test_upload_with_passphrase_protected_key() {
+    let Some(fixture) = Fixture::start("s3cret") else {
+        return;
+    };
+    let local_file = fixture.local_file("payload.pgp", "not really pgp");
+
+    let output = fixture
+        .upload(Auth::Key(&fixture.client_key), &[local_file])
+        .env("SFTP_PRIVATE_KEY_PASSPHRASE", "s3cret")
+        .output()
+        .unwrap();
+
+    assert!(
+        output.status.success(),
+        "upload failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        fs::read(fixture.remote_dir.join("payload.pgp")).unwrap(),
+        b"not really pgp"
+    );
+    let names: Vec<_> = fs::read_dir(&fixture.remote_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(
+        names,
+        ["payload.pgp"],
+        "temporary upload name was left behind"
+    );
+
+    let known_hosts = fs::read_to_string(fixture.path().join("known_hosts")).unwrap();
+    assert!(known_hosts.contains(&format!("[127.0.0.1]:{}", fixture.sshd.port)));
+}
+
+#[test]
+fn test_upload_reuses_one_connection_for_several_files() {
+    let Some(fixture) = Fixture::start("") else {
+        return;
+    };
+    let files: Vec<PathBuf> = (0..3)
+        .map(|i| fixture.local_file(&format!("file{i}.pgp"), &format!("payload {i}")))
+        .collect();
+
+    let output = fixture
+        .upload(Auth::Key(&fixture.client_key), &files)
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "upload failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(stdout.matches("Connected to").count(), 1, "{}", stdout);
+    for i in 0..3 {
+        assert!(fixture.remote_dir.join(format!("file{i}.pgp")).exists());
+    }
+}
+
+#[test]
+fn test_upload_refuses_changed_host_key() {
+    let Some(fixture) = Fixture::start("") else {
+        return;
+    };
+
+    // Record some other ECDSA key as the one we trust for this host.
+    let impostor = fixture.path().join("impostor_key");
+    ssh_keygen(&impostor, "ecdsa", "");
+    let impostor_pub = fs::read_to_string(impostor.with_extension("pub")).unwrap();
+    let fields: Vec<&str> = impostor_pub.split_whitespace().take(2).collect();
+    fs::write(
+        fixture.path().join("known_hosts"),
+        format!("[127.0.0.1]:{} {}\n", fixture.sshd.port, fields.join(" ")),
+    )
+    .unwrap();
+    let local_file = fixture.local_file("payload.pgp", "data");
+
+    let output = fixture
+        .upload(Auth::Key(&fixture.client_key), &[local_file])
+        .output()
+        .unwrap();
+
+    assert!(
+        !output.status.success(),
+        "upload to a changed host key succeeded"
+    );
+    assert!(String::from_utf8_lossy(&output.stderr).contains("HOST KEY CHANGED"));
+}
+
+#[test]
+fn test_upload_rejects_wrong_key() {
+    let Some(fixture) = Fixture::start("") else {
+        return;
+    };
+    let stranger = fixture.path().join("stranger_key");
+    ssh_keygen(&stranger, "rsa", "");
+    let local_file = fixture.local_file("payload.pgp", "data");
+
+    let output = fixture
+        .upload(Auth::Key(&stranger), &[local_file])
+        .output()
+        .unwrap();
+
+    assert!(
+        !output.status.success(),
+        "upload with an unknown key succeeded"
+    );
+}
+
+/// A wrong password is rejected without retrying. Set
+/// `VAULTSYNC_TEST_SSHD_PASSWORD` to the current user's password to also
+/// check a successful password login.
+#[test]
+fn test_upload_with_password() {
+    let Some(fixture) = Fixture::start("") else {
+        return;
+    };
+    let local_file = fixture.local_file("payload.pgp", "data");
+
+    let output = fixture
+        .upload(
+            Auth::Password("not-the-password"),
+            std::slice::from_ref(&local_file),
+        )
+        .output()
+        .unwrap();
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        !output.status.success(),
+        "upload with a wrong password succeeded"
+    );
+    assert!(stderr.contains("not retrying"), "{}", stderr);
+
+    let Ok(password) = env::var("VAULTSYNC_TEST_SSHD_PASSWORD") else {
+        return;
+    };
+    let output = fixture
+        .upload(Auth::Password(&password), &[local_file])
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "upload failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(fixture.remote_dir.join("payload.pgp").exists());
+}